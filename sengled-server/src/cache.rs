@@ -0,0 +1,39 @@
+use std::{collections::HashMap, fs, io, path::Path, sync::Arc, time::Duration};
+
+use crate::Account;
+
+// how long to wait after a change before writing the cache, so bursts of updates are written once
+pub(crate) const SAVE_DEBOUNCE: Duration = Duration::from_secs(5);
+
+// read a saved device cache, if there is one, as each account's devices by account id
+pub(crate) fn load(path: &Path) -> io::Result<HashMap<String, Vec<sengled::Device>>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(error) => return Err(error),
+    };
+
+    serde_json::from_str(&contents).map_err(io::Error::from)
+}
+
+// write the device cache, going through a temporary file so a partial write never replaces the
+// previous cache
+pub(crate) fn save(path: &Path, accounts: &[Arc<Account>]) -> io::Result<()> {
+    let devices = accounts
+        .iter()
+        .map(|account| {
+            let devices = account
+                .devices
+                .iter()
+                .map(|pair| pair.value().to_owned())
+                .collect::<Vec<_>>();
+            (account.id.to_owned(), devices)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    fs::write(&temp_path, serde_json::to_vec(&devices)?)?;
+    fs::rename(&temp_path, path)
+}
@@ -0,0 +1,88 @@
+use crate::{start_account, AccountConfig};
+
+const USAGE: &str = "usage: sengled-server [--account <id>] <command>
+
+commands:
+    list            list the account's devices
+    toggle <mac>    toggle a device on or off
+
+with no command, the HTTP server is started";
+
+// run a one-shot command against an account, for scripts and cron jobs
+pub(crate) async fn run(accounts: &[AccountConfig], args: &[String]) -> Result<(), String> {
+    let mut args = args.iter().map(String::as_str);
+    let mut account_id = None;
+    let mut command = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg {
+            "--account" => account_id = Some(args.next().ok_or(USAGE)?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => command.push(arg),
+        }
+    }
+
+    let account = match account_id {
+        Some(id) => accounts
+            .iter()
+            .find(|account| account.id == id)
+            .ok_or_else(|| format!("no account with id {:?}", id))?,
+        None => &accounts[0],
+    };
+
+    // validate the command before logging in
+    match command[..] {
+        ["list"] | ["toggle", _] => (),
+        _ => return Err(String::from(USAGE)),
+    }
+
+    let (mut client, event_handler) = start_account(account).await?;
+    event_handler.spawn_listener(&mut client);
+
+    let result = match command[..] {
+        ["toggle", mac] => toggle(&client, mac).await,
+        _ => list(&client).await,
+    };
+
+    // close even if the command failed, so queued messages are still sent
+    client.close().await.map_err(|error| error.to_string())?;
+    result
+}
+
+async fn list(client: &sengled::Client) -> Result<(), String> {
+    let mut devices = client
+        .wifi_devices()
+        .await
+        .map_err(|error| format!("failed to fetch devices: {}", error))?;
+    devices.sort_by(|a, b| a.mac.cmp(&b.mac));
+
+    for device in devices {
+        let state = match device.is_on() {
+            Some(true) => "on",
+            Some(false) => "off",
+            None => "unknown",
+        };
+
+        println!("{}\t{}\t{}", device.mac, state, device.display_name());
+    }
+
+    Ok(())
+}
+
+async fn toggle(client: &sengled::Client, mac: &str) -> Result<(), String> {
+    let device = client
+        .get_device(mac)
+        .await
+        .map_err(|error| format!("failed to fetch {}: {}", mac, error))?;
+
+    let on = client
+        .toggle_device(&device, device.is_on().unwrap_or(false))
+        .await
+        .map_err(|error| format!("failed to toggle {}: {}", mac, error))?;
+
+    println!("{} is now {}", device.mac, if on { "on" } else { "off" });
+    Ok(())
+}
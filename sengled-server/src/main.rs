@@ -1,27 +1,78 @@
-use std::{fs, process, sync::Arc};
+use std::{
+    collections::HashMap,
+    env, fs,
+    future::Future,
+    net::IpAddr,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
 use axum::{
     body::Body,
-    extract::{Request, State},
+    extract::{FromRef, Query, Request, State},
     http::StatusCode,
     middleware::Next,
     response::Response,
     routing::{get, post},
+    Router,
 };
 use dashmap::DashMap;
-use sengled::Event;
+use sengled::{AsDeviceMac, Event};
 use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::sync::{broadcast, watch, Notify, OwnedRwLockReadGuard, RwLock};
 use tower_http::cors::CorsLayer;
 
+mod cache;
+mod cli;
 mod routes;
+mod ws;
+
+// the id of the account given by the top-level username and password
+const DEFAULT_ACCOUNT: &str = "default";
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AccountConfig {
+    id: String,
+    username: String,
+    password: String,
+}
+
+impl AccountConfig {
+    // where the account's session is kept between runs
+    fn session_path(&self) -> String {
+        if self.id == DEFAULT_ACCOUNT {
+            String::from("session")
+        } else {
+            format!("session-{}", self.id)
+        }
+    }
+}
 
 #[derive(Deserialize, Serialize)]
 pub struct Config {
+    #[serde(default)]
     username: String,
+    #[serde(default)]
     password: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    accounts: Vec<AccountConfig>,
+    #[serde(default = "default_bind_address")]
+    bind_address: String,
     port: u16,
     require_auth: bool,
     auth_key: Option<String>,
+    #[serde(default)]
+    cache_path: Option<String>,
+}
+
+fn default_bind_address() -> String {
+    String::from("127.0.0.1")
 }
 
 impl Default for Config {
@@ -29,17 +80,373 @@ impl Default for Config {
         Self {
             username: "".into(),
             password: "".into(),
+            accounts: Vec::new(),
+            bind_address: default_bind_address(),
             port: 5005,
             require_auth: true,
             auth_key: Some(String::from("key")),
+            cache_path: None,
+        }
+    }
+}
+
+impl Config {
+    // the configured accounts, with the top-level username and password as shorthand for an
+    // account with the id `default`
+    fn accounts(&self) -> Vec<AccountConfig> {
+        let mut accounts = Vec::new();
+
+        if !self.username.is_empty() {
+            accounts.push(AccountConfig {
+                id: String::from(DEFAULT_ACCOUNT),
+                username: self.username.to_owned(),
+                password: self.password.to_owned(),
+            });
+        }
+
+        accounts.extend(self.accounts.iter().cloned());
+        accounts
+    }
+
+    // override settings with `SENGLED_*` variables, so credentials can be kept out of the file
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        if let Some(username) = var("SENGLED_USERNAME") {
+            self.username = username;
+        }
+        if let Some(password) = var("SENGLED_PASSWORD") {
+            self.password = password;
+        }
+        if let Some(bind_address) = var("SENGLED_BIND_ADDRESS") {
+            self.bind_address = bind_address;
+        }
+        if let Some(port) = var("SENGLED_PORT") {
+            self.port = port
+                .trim()
+                .parse()
+                .map_err(|_| format!("SENGLED_PORT is not a valid port: {:?}", port))?;
+        }
+        if let Some(require_auth) = var("SENGLED_REQUIRE_AUTH") {
+            self.require_auth = match require_auth.trim() {
+                "1" | "true" => true,
+                "0" | "false" => false,
+                _ => {
+                    return Err(format!(
+                        "SENGLED_REQUIRE_AUTH is not a boolean: {:?}",
+                        require_auth
+                    ))
+                }
+            };
+        }
+        if let Some(auth_key) = var("SENGLED_AUTH_KEY") {
+            self.auth_key = Some(auth_key);
+        }
+        if let Some(cache_path) = var("SENGLED_CACHE_PATH") {
+            self.cache_path = Some(cache_path);
+        }
+
+        Ok(())
+    }
+}
+
+// how long to wait for queued messages to be sent when shutting down
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how often each account's session is checked
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+// how many device updates a slow subscriber may fall behind before it misses some
+const UPDATE_CHANNEL_CAPACITY: usize = 256;
+
+/// A change to a device's attributes, as sent to live subscribers.
+#[derive(Clone, Serialize)]
+pub(crate) struct DeviceUpdate {
+    account: String,
+    device: String,
+    attributes: HashMap<String, String>,
+}
+
+struct Account {
+    id: String,
+    session_path: String,
+    client: Arc<RwLock<sengled::Client>>,
+    devices: DashMap<String, sengled::Device>,
+    // device updates handled by the listener, and when the last one arrived in unix seconds,
+    // or 0 if none has yet
+    events_processed: AtomicU64,
+    last_event_at: AtomicU64,
+}
+
+impl Account {
+    // run a client call, logging in again and retrying once if the session was rejected
+    async fn with_client<T, F, Fut>(&self, call: F) -> Result<T, sengled::Error>
+    where
+        F: Fn(OwnedRwLockReadGuard<sengled::Client>) -> Fut,
+        Fut: Future<Output = Result<T, sengled::Error>>,
+    {
+        match call(Arc::clone(&self.client).read_owned().await).await {
+            Err(sengled::Error::SessionExpired) => {
+                self.refresh_session().await?;
+                call(Arc::clone(&self.client).read_owned().await).await
+            }
+            result => result,
+        }
+    }
+
+    // log in again, saving the new session for the next run
+    async fn refresh_session(&self) -> Result<(), sengled::Error> {
+        let mut client = self.client.write().await;
+        client.relogin().await?;
+        save_session(&self.session_path, &client);
+
+        Ok(())
+    }
+
+    // check the session now and then, so that it is refreshed before requests start failing
+    async fn keep_session_fresh(&self, state: &AppState) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(SESSION_CHECK_INTERVAL) => (),
+                _ = state.shutting_down() => break,
+            }
+
+            let valid = self.client.read().await.validate_session().await;
+            if let Ok(false) = valid {
+                if let Err(error) = self.refresh_session().await {
+                    eprintln!("failed to refresh the session for {}: {}", self.id, error);
+                }
+            }
+        }
+    }
+}
+
+fn save_session(path: &str, client: &sengled::Client) {
+    if let Some(session) = client.session() {
+        if let Err(error) = fs::write(path, session) {
+            eprintln!("failed to save session to {}: {}", path, error);
         }
     }
 }
 
 struct AppState {
     config: Config,
-    client: sengled::Client,
-    devices: DashMap<String, sengled::Device>,
+    accounts: Vec<Arc<Account>>,
+    updates: broadcast::Sender<DeviceUpdate>,
+    cache_changed: Notify,
+    shutdown: watch::Sender<bool>,
+}
+
+impl AppState {
+    // wait until the server starts shutting down
+    async fn shutting_down(&self) {
+        let _ = self
+            .shutdown
+            .subscribe()
+            .wait_for(|&shutdown| shutdown)
+            .await;
+    }
+}
+
+// the state of routes that act on a single account
+#[derive(Clone, FromRef)]
+struct AccountState {
+    app: Arc<AppState>,
+    account: Arc<Account>,
+}
+
+// log in to an account, reusing its saved session if it is still valid
+async fn login_account(config: &AccountConfig) -> Result<sengled::Client, String> {
+    // get session ID if present
+    let session = fs::read_to_string(config.session_path()).ok();
+
+    // set up the client
+    let mut client = sengled::Client::new(&config.username, &config.password)
+        .with_skip_server_check()
+        .with_preferred_qos(sengled::QoS::AtMostOnce);
+
+    if let Some(session) = session {
+        client.set_session(session);
+    }
+
+    if client.session().is_none() || !client.validate_session().await.unwrap_or(false) {
+        client
+            .login()
+            .await
+            .map_err(|error| format!("failed to login to {}: {}", config.id, error))?;
+        save_session(&config.session_path(), &client);
+    }
+
+    Ok(client)
+}
+
+// log in to an account and start its client
+async fn start_account(
+    config: &AccountConfig,
+) -> Result<(sengled::Client, sengled::EventHandler), String> {
+    let mut client = login_account(config).await?;
+    let event_handler = client
+        .start()
+        .await
+        .map_err(|error| format!("failed to start client for {}: {}", config.id, error))?
+        .with_auto_reconnect(sengled::ReconnectPolicy::default());
+
+    Ok((client, event_handler))
+}
+
+// keep an account's device cache up to date until shutdown, then hand the event handler back
+// so it can flush the disconnect
+async fn listen(
+    state: Arc<AppState>,
+    account: Arc<Account>,
+    mut event_handler: sengled::EventHandler,
+) -> sengled::EventHandler {
+    loop {
+        let event = tokio::select! {
+            event = event_handler.poll() => event,
+            _ = state.shutting_down() => break,
+        };
+
+        let event = match event {
+            Ok(event) => event,
+            Err(sengled::Error::Payload { topic, snippet }) => {
+                eprintln!("ignoring unexpected payload on {}: {}", topic, snippet);
+                continue;
+            }
+            Err(_) => break,
+        };
+
+        match event {
+            Event::DeviceAttributesChanged {
+                device, attributes, ..
+            } => {
+                let attributes = attributes.into_iter().collect::<HashMap<_, _>>();
+
+                account.events_processed.fetch_add(1, Ordering::Relaxed);
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs());
+                account.last_event_at.store(now, Ordering::Relaxed);
+
+                if let Some(mut cached) = account.devices.get_mut(&device) {
+                    cached.attributes.extend(attributes.clone());
+                    state.cache_changed.notify_one();
+                }
+
+                // this only fails when nobody is listening
+                let _ = state.updates.send(DeviceUpdate {
+                    account: account.id.to_owned(),
+                    device,
+                    attributes,
+                });
+            }
+            Event::Reconnected => {
+                // refresh the device cache, since updates may have been missed
+                let devices = account
+                    .with_client(|client| async move { client.wifi_devices().await })
+                    .await;
+                if let Ok(devices) = devices {
+                    for device in devices {
+                        account.devices.insert(device.normalized_mac(), device);
+                    }
+                    state.cache_changed.notify_one();
+                }
+            }
+            Event::Connected | Event::Disconnected | Event::Raw { .. } => (),
+        }
+    }
+
+    event_handler
+}
+
+// the routes that act on a single account
+fn account_routes(state: AccountState) -> Router {
+    Router::new()
+        .route("/devices", get(routes::get_devices))
+        .route("/devices", post(routes::set_device_attributes))
+        .route(
+            "/devices/:id",
+            get(routes::get_device).patch(routes::patch_device),
+        )
+        .route("/devices/:id/toggle", post(routes::toggle_device))
+        .route("/events", get(routes::events))
+        .route("/ws", get(ws::upgrade))
+        .with_state(state)
+}
+
+// set up the webapp. each account's routes are under `/accounts/:acct`, and the first account's
+// are also at the root
+fn app(state: &Arc<AppState>) -> Router {
+    let mut app = Router::new();
+    for account in &state.accounts {
+        app = app.nest(
+            &format!("/accounts/{}", account.id),
+            account_routes(AccountState {
+                app: Arc::clone(state),
+                account: Arc::clone(account),
+            }),
+        );
+    }
+
+    app.merge(account_routes(AccountState {
+        app: Arc::clone(state),
+        account: Arc::clone(&state.accounts[0]),
+    }))
+    .route_layer(axum::middleware::from_fn_with_state(
+        Arc::clone(state),
+        authorization_middleware,
+    ))
+    // probes and scrapers can't be expected to authenticate
+    .route("/health", get(routes::health).with_state(Arc::clone(state)))
+    .route(
+        "/metrics",
+        get(routes::metrics).with_state(Arc::clone(state)),
+    )
+    .layer(CorsLayer::permissive())
+}
+
+// wait for SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => (),
+        _ = terminate => (),
+    }
+}
+
+// account ids become part of the route path, so they are limited to characters that can't be
+// mistaken for a separator, path parameter or wildcard
+fn is_valid_account_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// compare a provided key against the configured one in constant time, accepting an optional
+// `Bearer` scheme and surrounding whitespace
+fn auth_key_matches(provided: &str, expected: &str) -> bool {
+    let provided = provided.trim();
+    let provided = match provided.split_once(' ') {
+        Some((scheme, key)) if scheme.eq_ignore_ascii_case("bearer") => key.trim(),
+        _ => provided,
+    };
+
+    provided.as_bytes().ct_eq(expected.as_bytes()).into()
 }
 
 async fn authorization_middleware(
@@ -51,7 +458,15 @@ async fn authorization_middleware(
         return next.run(request).await;
     }
 
-    let auth = match request.headers().get("Authorization") {
+    // browsers can't set headers on websocket or event source requests, so the key may also be
+    // passed as the `auth_key` query parameter
+    let query = Query::<HashMap<String, String>>::try_from_uri(request.uri()).ok();
+    let auth = match request
+        .headers()
+        .get("Authorization")
+        .map(|auth| auth.to_str().unwrap_or("").to_owned())
+        .or_else(|| query.and_then(|Query(mut query)| query.remove("auth_key")))
+    {
         Some(auth) => auth,
         None => {
             return Response::builder()
@@ -61,7 +476,7 @@ async fn authorization_middleware(
         }
     };
 
-    if auth.to_str().unwrap_or("") != state.config.auth_key.as_deref().unwrap_or_default() {
+    if !auth_key_matches(&auth, state.config.auth_key.as_deref().unwrap_or_default()) {
         return Response::builder()
             .status(StatusCode::FORBIDDEN)
             .body(Body::empty())
@@ -73,9 +488,11 @@ async fn authorization_middleware(
 
 #[tokio::main]
 async fn main() {
-    // parse config
-    let config: Config = if let Ok(config) = fs::read_to_string("config.yml") {
+    // parse config, with environment variables taking precedence over the file
+    let mut config: Config = if let Ok(config) = fs::read_to_string("config.yml") {
         serde_yaml::from_str(&config).expect("failed to deserialize config.yml")
+    } else if env::var_os("SENGLED_USERNAME").is_some() {
+        Config::default()
     } else {
         fs::write(
             "config.yml",
@@ -86,68 +503,366 @@ async fn main() {
         process::exit(1);
     };
 
-    // get session ID if present
-    let session = fs::read_to_string("session").ok();
+    if let Err(error) = config.apply_env(|name| env::var(name).ok()) {
+        eprintln!("invalid environment: {}", error);
+        process::exit(1);
+    }
 
-    // set up the client
-    let mut client = sengled::Client::new(&config.username, &config.password)
-        .with_skip_server_check()
-        .with_preferred_qos(sengled::QoS::AtMostOnce);
+    let account_configs = config.accounts();
+    if account_configs.is_empty() {
+        eprintln!("no accounts in config.yml! set a username and password, or list accounts");
+        process::exit(1);
+    }
 
-    if let Some(session) = session {
-        client.set_session(session);
-    } else {
-        client.login().await.expect("failed to login");
+    for (i, account) in account_configs.iter().enumerate() {
+        if !is_valid_account_id(&account.id) {
+            eprintln!(
+                "account id {:?} in config.yml must only use letters, digits, `_` and `-`",
+                account.id
+            );
+            process::exit(1);
+        }
+
+        if account_configs[..i]
+            .iter()
+            .any(|other| other.id == account.id)
+        {
+            eprintln!(
+                "account id {:?} is used more than once in config.yml",
+                account.id
+            );
+            process::exit(1);
+        }
     }
 
-    let mut event_handler = client.start().await.expect("failed to start client");
+    // run a one-shot command instead of the server if one was given
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        if let Err(error) = cli::run(&account_configs, &args).await {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let bind_address: IpAddr = match config.bind_address.parse() {
+        Ok(address) => address,
+        Err(_) => {
+            eprintln!(
+                "invalid bind_address in config.yml: {:?} is not an IP address",
+                config.bind_address
+            );
+            process::exit(1);
+        }
+    };
+
+    let mut accounts = Vec::new();
+    let mut event_handlers = Vec::new();
+    for account_config in &account_configs {
+        let (client, event_handler) = start_account(account_config).await.unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            process::exit(1);
+        });
+
+        accounts.push(Arc::new(Account {
+            id: account_config.id.to_owned(),
+            session_path: account_config.session_path(),
+            client: Arc::new(RwLock::new(client)),
+            devices: DashMap::new(),
+            events_processed: AtomicU64::new(0),
+            last_event_at: AtomicU64::new(0),
+        }));
+        event_handlers.push(event_handler);
+    }
 
     let port = config.port;
     let state = Arc::new(AppState {
         config,
-        client,
-        devices: DashMap::new(),
+        accounts,
+        updates: broadcast::channel(UPDATE_CHANNEL_CAPACITY).0,
+        cache_changed: Notify::new(),
+        shutdown: watch::channel(false).0,
     });
 
-    // device cache and subscription handler
-    for device in state.client.get_wifi_devices_and_subscribe().await.unwrap() {
-        state.devices.insert(device.mac.to_owned(), device);
+    // warm the device cache from disk, if persisting it
+    let cache_path = state.config.cache_path.as_ref().map(PathBuf::from);
+    if let Some(cache_path) = &cache_path {
+        match cache::load(cache_path) {
+            Ok(mut cached) => {
+                for account in &state.accounts {
+                    for device in cached.remove(&account.id).unwrap_or_default() {
+                        account.devices.insert(device.normalized_mac(), device);
+                    }
+                }
+            }
+            Err(error) => eprintln!("failed to load device cache: {}", error),
+        }
     }
 
-    let listener_state = Arc::clone(&state);
-    tokio::spawn(async move {
-        while let Ok(event) = event_handler.poll().await {
-            match event {
-                Event::DeviceAttributesChanged { device, attributes } => {
-                    for (key, value) in attributes {
-                        let mut device = match listener_state.devices.get_mut(&device) {
-                            Some(device) => device,
-                            None => continue,
-                        };
+    // device cache and subscription handlers
+    let mut listener_handles = Vec::new();
+    let mut session_handles = Vec::new();
+    for (account, event_handler) in state.accounts.iter().zip(event_handlers) {
+        let devices = account
+            .with_client(|client| async move { client.get_wifi_devices_and_subscribe().await })
+            .await
+            .unwrap();
+        for device in devices {
+            account.devices.insert(device.normalized_mac(), device);
+        }
 
-                        device.attributes.insert(key, value);
-                    }
+        let session_state = Arc::clone(&state);
+        let session_account = Arc::clone(account);
+        session_handles.push(tokio::spawn(async move {
+            session_account.keep_session_fresh(&session_state).await
+        }));
+
+        listener_handles.push(tokio::spawn(listen(
+            Arc::clone(&state),
+            Arc::clone(account),
+            event_handler,
+        )));
+    }
+    state.cache_changed.notify_one();
+
+    // periodically persist the device cache
+    let cache_handle = cache_path.clone().map(|cache_path| {
+        let cache_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = cache_state.cache_changed.notified() => (),
+                    _ = cache_state.shutting_down() => break,
+                }
+                tokio::time::sleep(cache::SAVE_DEBOUNCE).await;
+
+                if let Err(error) = cache::save(&cache_path, &cache_state.accounts) {
+                    eprintln!("failed to save device cache: {}", error);
                 }
             }
-        }
+        })
     });
 
-    // set up webapp
-    let app = axum::Router::new()
-        .route("/devices", get(routes::get_devices))
-        .route("/devices", post(routes::set_device_attributes))
-        .route("/devices/:id", get(routes::get_device))
-        .route("/devices/:id/toggle", post(routes::toggle_device))
-        .route_layer(axum::middleware::from_fn_with_state(
-            Arc::clone(&state),
-            authorization_middleware,
-        ))
-        .layer(CorsLayer::permissive())
-        .with_state(Arc::clone(&state));
+    let app = app(&state);
+    let listener = tokio::net::TcpListener::bind((bind_address, port))
+        .await
+        .unwrap();
 
-    let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+    let shutdown_state = Arc::clone(&state);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            shutdown_state.shutdown.send_replace(true);
+        })
         .await
         .unwrap();
 
-    axum::serve(listener, app).await.unwrap();
+    // stop the background tasks, which release their hold on the state
+    let mut event_handlers = Vec::new();
+    for listener_handle in listener_handles {
+        event_handlers.push(listener_handle.await.unwrap());
+    }
+    for session_handle in session_handles {
+        let _ = session_handle.await;
+    }
+    if let Some(cache_handle) = cache_handle {
+        let _ = cache_handle.await;
+    }
+
+    if let Some(cache_path) = &cache_path {
+        if let Err(error) = cache::save(cache_path, &state.accounts) {
+            eprintln!("failed to save device cache: {}", error);
+        }
+    }
+
+    let Ok(state) = Arc::try_unwrap(state) else {
+        eprintln!("server state is still in use, exiting without closing the clients");
+        return;
+    };
+
+    for (account, mut event_handler) in state.accounts.into_iter().zip(event_handlers) {
+        let (id, client) = match Arc::try_unwrap(account) {
+            Ok(Account { id, client, .. }) => match Arc::try_unwrap(client) {
+                Ok(client) => (id, client.into_inner()),
+                Err(_) => {
+                    eprintln!("account {} is still in use, not closing its client", id);
+                    continue;
+                }
+            },
+            Err(account) => {
+                eprintln!(
+                    "account {} is still in use, not closing its client",
+                    account.id
+                );
+                continue;
+            }
+        };
+
+        // keep the event loop running until the disconnect has been sent
+        let flush = tokio::spawn(async move { while event_handler.poll().await.is_ok() {} });
+
+        if let Err(error) = client.close().await {
+            eprintln!("failed to close the client for {}: {}", id, error);
+        }
+
+        if tokio::time::timeout(CLOSE_TIMEOUT, flush).await.is_err() {
+            eprintln!(
+                "timed out sending queued messages for {} before exiting",
+                id
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the auth key of the test state
+    pub(crate) const AUTH_KEY: &str = "key";
+
+    // a WIFI bulb as the API lists it
+    pub(crate) fn bulb(mac: &str, switch: &str) -> sengled::Device {
+        serde_json::from_value(serde_json::json!({
+            "category": "wifielement",
+            "deviceUuid": mac,
+            "typeCode": "W21-N13",
+            "attributeList": [{ "name": "switch", "value": switch }],
+        }))
+        .unwrap()
+    }
+
+    // the state of a server with one account backed by a mock client, caching the given devices
+    pub(crate) async fn mock_state(
+        devices: Vec<sengled::Device>,
+    ) -> (Arc<AppState>, sengled::MockTransport) {
+        let (mut client, mock) = sengled::Client::new_mock();
+        let _ = client.start().await.unwrap();
+
+        let account = Account {
+            id: String::from(DEFAULT_ACCOUNT),
+            session_path: String::from("session"),
+            client: Arc::new(RwLock::new(client)),
+            devices: DashMap::new(),
+            events_processed: AtomicU64::new(0),
+            last_event_at: AtomicU64::new(0),
+        };
+        for device in devices {
+            account.devices.insert(device.normalized_mac(), device);
+        }
+
+        let state = Arc::new(AppState {
+            config: Config::default(),
+            accounts: vec![Arc::new(account)],
+            updates: broadcast::channel(UPDATE_CHANNEL_CAPACITY).0,
+            cache_changed: Notify::new(),
+            shutdown: watch::channel(false).0,
+        });
+
+        (state, mock)
+    }
+
+    // send a request to the app, returning the status and the body parsed as JSON, if it is
+    pub(crate) async fn send(
+        state: &Arc<AppState>,
+        request: axum::http::request::Builder,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, Option<serde_json::Value>) {
+        use tower::Service;
+
+        let request = match body {
+            Some(body) => request
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        };
+        let response = app(state).call(request.unwrap()).await.unwrap();
+
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).ok())
+    }
+
+    #[test]
+    fn account_ids_are_limited_to_path_safe_characters() {
+        assert!(is_valid_account_id("default"));
+        assert!(is_valid_account_id("Home_2-b"));
+
+        for id in ["", "a/b", ":acct", "*rest", "a b", "caf\u{e9}"] {
+            assert!(!is_valid_account_id(id), "{id:?}");
+        }
+    }
+
+    #[test]
+    fn auth_keys_match_bare_and_bearer_forms() {
+        assert!(auth_key_matches("key", "key"));
+        assert!(auth_key_matches(" key ", "key"));
+        assert!(auth_key_matches("Bearer key", "key"));
+        assert!(auth_key_matches("bearer  key", "key"));
+
+        assert!(!auth_key_matches("Bearer other", "key"));
+        assert!(!auth_key_matches("Basic key", "key"));
+        assert!(!auth_key_matches("ke", "key"));
+        assert!(!auth_key_matches("", "key"));
+    }
+
+    #[tokio::test]
+    async fn requests_authenticate_with_either_form() {
+        let (state, _mock) = mock_state(Vec::new()).await;
+
+        for auth in [AUTH_KEY.to_owned(), format!("Bearer {}", AUTH_KEY)] {
+            let request = Request::builder()
+                .uri("/devices")
+                .header("Authorization", auth);
+            assert_eq!(send(&state, request, None).await.0, StatusCode::OK);
+        }
+
+        let request = Request::builder()
+            .uri("/devices")
+            .header("Authorization", "Bearer wrong");
+        assert_eq!(send(&state, request, None).await.0, StatusCode::FORBIDDEN);
+
+        let request = Request::builder().uri("/devices");
+        assert_eq!(
+            send(&state, request, None).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[test]
+    fn environment_variables_override_the_file() {
+        let mut config: Config = serde_yaml::from_str(
+            "username: file-user\npassword: file-password\nport: 5005\nrequire_auth: true\nauth_key: file-key\n",
+        )
+        .unwrap();
+
+        let env = HashMap::from([
+            ("SENGLED_PASSWORD", "env-password"),
+            ("SENGLED_PORT", "8080"),
+            ("SENGLED_REQUIRE_AUTH", "false"),
+        ]);
+        config
+            .apply_env(|name| env.get(name).map(|value| String::from(*value)))
+            .unwrap();
+
+        assert_eq!(config.username, "file-user");
+        assert_eq!(config.password, "env-password");
+        assert_eq!(config.port, 8080);
+        assert!(!config.require_auth);
+        assert_eq!(config.auth_key.as_deref(), Some("file-key"));
+        assert_eq!(config.bind_address, "127.0.0.1");
+    }
+
+    #[test]
+    fn invalid_environment_variables_are_rejected() {
+        let mut config = Config::default();
+        assert!(config
+            .apply_env(|name| (name == "SENGLED_PORT").then(|| String::from("http")))
+            .is_err());
+        assert!(config
+            .apply_env(|name| (name == "SENGLED_REQUIRE_AUTH").then(|| String::from("maybe")))
+            .is_err());
+    }
 }
@@ -1,15 +1,52 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Write,
+    sync::{atomic::Ordering, Arc},
+};
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     Json,
 };
+use futures::{Stream, StreamExt};
 use serde::Deserialize;
 use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{Account, AppState};
 
-use crate::AppState;
+// look up a cached device by its MAC, in any of the forms `normalize_mac` accepts
+pub(crate) fn lookup_device<'a>(
+    account: &'a Account,
+    id: &str,
+) -> Result<dashmap::mapref::one::Ref<'a, String, sengled::Device>, sengled::Error> {
+    account
+        .devices
+        .get(&sengled::normalize_mac(id))
+        .ok_or_else(|| sengled::Error::DeviceNotFound(id.to_owned()))
+}
+
+// the MAC to send commands for a device to. the cached device's own form is used when it is
+// known, since MQTT topics are case-sensitive
+fn command_mac(account: &Account, id: &str) -> String {
+    lookup_device(account, id)
+        .map(|device| device.mac.to_owned())
+        .unwrap_or_else(|_| id.to_owned())
+}
+
+// translate a client error into a response status
+fn error_status(error: &sengled::Error) -> StatusCode {
+    match error {
+        sengled::Error::DeviceNotFound(_) => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
 
 #[derive(Deserialize)]
 pub(crate) struct SetDeviceAttributes {
@@ -17,32 +54,88 @@ pub(crate) struct SetDeviceAttributes {
     attributes: HashMap<String, String>,
 }
 
-// set attributes on a single device
+// set attributes on many devices at once, reporting the devices that failed
 pub(crate) async fn set_device_attributes(
-    State(state): State<Arc<AppState>>,
+    State(account): State<Arc<Account>>,
     Json(payload): Json<Vec<SetDeviceAttributes>>,
-) -> StatusCode {
-    for bulk in payload {
-        for device in bulk.devices {
-            if state
-                .client
-                .set_device_attributes(device, &bulk.attributes.iter().collect::<Vec<_>>())
-                .await
-                .is_err()
-            {
-                return StatusCode::INTERNAL_SERVER_ERROR;
+) -> Response {
+    let updates = payload
+        .iter()
+        .flat_map(|bulk| {
+            let attributes = bulk.attributes.iter().collect::<Vec<_>>();
+            bulk.devices
+                .iter()
+                .map(move |device| (device, attributes.clone()))
+        })
+        .map(|(device, attributes)| {
+            let account = &account;
+            async move {
+                let mac = &command_mac(account, device);
+                let result = account
+                    .with_client(|client| {
+                        let attributes = &attributes;
+                        async move { client.set_device_attributes(mac, attributes).await }
+                    })
+                    .await;
+                (device, result)
             }
-        }
+        });
+
+    let results = futures::future::join_all(updates).await;
+    let total = results.len();
+    let failures = results
+        .into_iter()
+        .filter_map(|(device, result)| {
+            result
+                .err()
+                .map(|error| json!({ "device": device, "error": error.to_string() }))
+        })
+        .collect::<Vec<_>>();
+
+    if failures.is_empty() {
+        return StatusCode::OK.into_response();
     }
 
-    StatusCode::OK
+    // only a partial failure is a multi-status, when nothing was set the request failed
+    let status = if failures.len() == total {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+
+    (status, Json(json!({ "failures": failures }))).into_response()
+}
+
+// optional filters on the device list, where invalid values are rejected with a 400
+#[derive(Deserialize)]
+pub(crate) struct DeviceFilter {
+    category: Option<String>,
+    online: Option<bool>,
+}
+
+impl DeviceFilter {
+    fn matches(&self, device: &sengled::Device) -> bool {
+        let category = self
+            .category
+            .as_ref()
+            .is_none_or(|category| device.category.eq_ignore_ascii_case(category));
+        let online = self
+            .online
+            .is_none_or(|online| device.is_online() == Some(online));
+
+        category && online
+    }
 }
 
-pub(crate) async fn get_devices(State(state): State<Arc<AppState>>) -> Response {
+pub(crate) async fn get_devices(
+    State(account): State<Arc<Account>>,
+    Query(filter): Query<DeviceFilter>,
+) -> Response {
     Json(
-        state
+        account
             .devices
             .iter()
+            .filter(|pair| filter.matches(pair.value()))
             .map(|pair| pair.value().to_owned())
             .collect::<Vec<_>>(),
     )
@@ -50,39 +143,379 @@ pub(crate) async fn get_devices(State(state): State<Arc<AppState>>) -> Response
 }
 
 pub(crate) async fn get_device(
+    State(account): State<Arc<Account>>,
+    Path(id): Path<String>,
+) -> Response {
+    match lookup_device(&account, &id) {
+        Ok(device) => Json(device.to_owned()).into_response(),
+        Err(error) => error_status(&error).into_response(),
+    }
+}
+
+// set attributes on a single device, returning the updated device
+pub(crate) async fn patch_device(
     State(state): State<Arc<AppState>>,
+    State(account): State<Arc<Account>>,
     Path(id): Path<String>,
+    Json(attributes): Json<HashMap<String, String>>,
 ) -> Response {
-    let device = state.devices.get(&id);
+    if let Err(error) = lookup_device(&account, &id) {
+        return error_status(&error).into_response();
+    }
 
-    match device {
-        Some(device) => Json(device.to_owned()).into_response(),
-        None => StatusCode::NOT_FOUND.into_response(),
+    let mac = &command_mac(&account, &id);
+    let pairs = attributes.iter().collect::<Vec<_>>();
+    if let Err(error) = account
+        .with_client(|client| {
+            let pairs = &pairs;
+            async move { client.set_device_attributes(mac, pairs).await }
+        })
+        .await
+    {
+        return error_status(&error).into_response();
     }
+
+    let Some(mut device) = account.devices.get_mut(&sengled::normalize_mac(&id)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    device.attributes.extend(attributes);
+    state.cache_changed.notify_one();
+
+    Json(device.to_owned()).into_response()
 }
 
 pub(crate) async fn toggle_device(
-    State(state): State<Arc<AppState>>,
+    State(account): State<Arc<Account>>,
     Path(id): Path<String>,
 ) -> Response {
-    let Some(device) = state.devices.get(&id) else {
-        return StatusCode::NOT_FOUND.into_response();
+    let device = match lookup_device(&account, &id) {
+        Ok(device) => device,
+        Err(error) => return error_status(&error).into_response(),
     };
 
-    let Some(switch) = device.get_attribute("switch") else {
+    let Some(switch) = device.is_on() else {
         return StatusCode::BAD_REQUEST.into_response();
     };
 
-    let new_switch = if switch == "0" { "1" } else { "0" };
+    // don't hold the cache entry while publishing, the listener may need to update it
+    let mac = &device.mac.to_owned();
+    drop(device);
 
-    if state
-        .client
-        .set_device_attribute(&device.mac, "switch", new_switch)
-        .await
-        .is_err()
-    {
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    let toggled = account
+        .with_client(|client| async move { client.toggle_device(mac, switch).await })
+        .await;
+
+    match toggled {
+        Ok(new_switch) => {
+            Json(json!({ "value": if new_switch { "1" } else { "0" } })).into_response()
+        }
+        Err(error) => error_status(&error).into_response(),
+    }
+}
+
+// stream device updates as server-sent events until the client goes away
+pub(crate) async fn events(
+    State(state): State<Arc<AppState>>,
+    State(account): State<Arc<Account>>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let receiver = state.updates.subscribe();
+    let stream = futures::stream::unfold(receiver, move |mut receiver| {
+        let account = Arc::clone(&account);
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(update) if update.account != account.id => continue,
+                    Ok(update) => {
+                        let event = sse::Event::default()
+                            .event("device")
+                            .json_data(&update)
+                            .unwrap();
+                        return Some((Ok(event), receiver));
+                    }
+                    // a slow client just misses the updates it fell behind on
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    // end the stream on shutdown, otherwise open streams would keep the server from stopping
+    let shutdown_state = Arc::clone(&state);
+    let stream = stream.take_until(async move { shutdown_state.shutting_down().await });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+// report whether every account is logged in and connected, for orchestration probes
+pub(crate) async fn health(State(state): State<Arc<AppState>>) -> Response {
+    let mut accounts = Vec::new();
+    let mut logged_in = true;
+    let mut mqtt_connected = true;
+    for account in &state.accounts {
+        let client = account.client.read().await;
+        let account_logged_in = client.session().is_some();
+        let account_connected = client.is_connected();
+        logged_in &= account_logged_in;
+        mqtt_connected &= account_connected;
+
+        accounts.push(json!({
+            "id": account.id,
+            "logged_in": account_logged_in,
+            "mqtt_connected": account_connected,
+            "device_count": account.devices.len(),
+        }));
+    }
+
+    let device_count = state
+        .accounts
+        .iter()
+        .map(|account| account.devices.len())
+        .sum::<usize>();
+
+    let status = if logged_in && mqtt_connected {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(json!({
+            "logged_in": logged_in,
+            "mqtt_connected": mqtt_connected,
+            "device_count": device_count,
+            "accounts": accounts,
+        })),
+    )
+        .into_response()
+}
+
+// report per-account counters in the Prometheus text exposition format
+pub(crate) async fn metrics(State(state): State<Arc<AppState>>) -> Response {
+    let mut samples = HashMap::<&str, Vec<(&str, u64)>>::new();
+    for account in &state.accounts {
+        let client = account.client.read().await;
+        let client_metrics = client.metrics();
+
+        for (name, value) in [
+            ("sengled_devices", account.devices.len() as u64),
+            ("sengled_mqtt_connected", client.is_connected() as u64),
+            (
+                "sengled_events_processed_total",
+                account.events_processed.load(Ordering::Relaxed),
+            ),
+            (
+                "sengled_last_event_timestamp_seconds",
+                account.last_event_at.load(Ordering::Relaxed),
+            ),
+            (
+                "sengled_mqtt_events_received_total",
+                client_metrics.events_received,
+            ),
+            (
+                "sengled_publishes_sent_total",
+                client_metrics.publishes_sent,
+            ),
+            ("sengled_reconnects_total", client_metrics.reconnects),
+            ("sengled_errors_total", client_metrics.errors),
+        ] {
+            samples.entry(name).or_default().push((&account.id, value));
+        }
+    }
+
+    let mut body = String::new();
+    for (name, kind, help) in METRICS {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} {}", name, kind);
+
+        for (account, value) in samples.get(name).into_iter().flatten() {
+            // label values escape backslashes and quotes
+            let account = account.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(body, "{}{{account=\"{}\"}} {}", name, account, value);
+        }
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+// the name, type and description of every metric, in the order they are reported
+const METRICS: [(&str, &str, &str); 8] = [
+    (
+        "sengled_devices",
+        "gauge",
+        "Devices cached for the account.",
+    ),
+    (
+        "sengled_mqtt_connected",
+        "gauge",
+        "Whether the account's MQTT connection is up.",
+    ),
+    (
+        "sengled_events_processed_total",
+        "counter",
+        "Device updates processed by the server.",
+    ),
+    (
+        "sengled_last_event_timestamp_seconds",
+        "gauge",
+        "Unix time of the last device update, or 0 if there has been none.",
+    ),
+    (
+        "sengled_mqtt_events_received_total",
+        "counter",
+        "Messages received from the MQTT server.",
+    ),
+    (
+        "sengled_publishes_sent_total",
+        "counter",
+        "Publishes queued for the MQTT server.",
+    ),
+    (
+        "sengled_reconnects_total",
+        "counter",
+        "Reconnects after the MQTT connection dropped.",
+    ),
+    (
+        "sengled_errors_total",
+        "counter",
+        "Failed publishes, bad payloads and connection errors.",
+    ),
+];
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, Request, StatusCode};
+    use serde_json::json;
+
+    use crate::tests::{bulb, mock_state, send, AUTH_KEY};
+
+    #[tokio::test]
+    async fn patching_a_device_sets_its_attributes() {
+        let (state, mock) = mock_state(vec![bulb("B0:CE:18:00:00:01", "0")]).await;
+
+        let request = Request::builder()
+            .method(Method::PATCH)
+            .uri("/devices/b0:ce:18:00:00:01")
+            .header("Authorization", AUTH_KEY);
+        let (status, body) = send(&state, request, Some(json!({ "switch": "1" }))).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let body = body.unwrap();
+        assert_eq!(body["mac"], "B0:CE:18:00:00:01");
+        assert_eq!(body["attributes"]["switch"], "1");
+
+        let published = mock.published();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].0, "wifielement/B0:CE:18:00:00:01/update");
+    }
+
+    #[tokio::test]
+    async fn patching_an_unknown_device_is_not_found() {
+        let (state, mock) = mock_state(Vec::new()).await;
+
+        let request = Request::builder()
+            .method(Method::PATCH)
+            .uri("/devices/B0:CE:18:00:00:01")
+            .header("Authorization", AUTH_KEY);
+        let (status, _) = send(&state, request, Some(json!({ "switch": "1" }))).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(mock.published().is_empty());
     }
 
-    Json(json!({ "value": new_switch })).into_response()
+    // the MACs of the devices listed at `uri`, sorted
+    async fn listed_macs(uri: &str) -> (StatusCode, Vec<String>) {
+        let mut offline = bulb("B0:CE:18:00:00:02", "0");
+        offline
+            .attributes
+            .insert(String::from("online"), String::from("0"));
+        let mut online = bulb("B0:CE:18:00:00:01", "1");
+        online
+            .attributes
+            .insert(String::from("online"), String::from("1"));
+        let mut plug = bulb("B0:CE:18:00:00:03", "1");
+        plug.category = String::from("plug");
+        plug.attributes
+            .insert(String::from("online"), String::from("1"));
+        let (state, _mock) = mock_state(vec![offline, online, plug]).await;
+
+        let request = Request::builder()
+            .uri(uri)
+            .header("Authorization", AUTH_KEY);
+        let (status, body) = send(&state, request, None).await;
+
+        let mut macs = body
+            .and_then(|body| body.as_array().cloned())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|device| device["mac"].as_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        macs.sort_unstable();
+        (status, macs)
+    }
+
+    #[tokio::test]
+    async fn devices_filter_by_category_and_online() {
+        assert_eq!(listed_macs("/devices").await.1.len(), 3);
+        assert_eq!(
+            listed_macs("/devices?category=WIFIELEMENT").await.1,
+            vec!["B0:CE:18:00:00:01", "B0:CE:18:00:00:02"]
+        );
+        assert_eq!(
+            listed_macs("/devices?online=true").await.1,
+            vec!["B0:CE:18:00:00:01", "B0:CE:18:00:00:03"]
+        );
+        assert_eq!(
+            listed_macs("/devices?category=wifielement&online=false&sort=name")
+                .await
+                .1,
+            vec!["B0:CE:18:00:00:02"]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_device_filters_are_bad_requests() {
+        let (status, _) = listed_macs("/devices?online=sometimes").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_exposed_without_auth() {
+        use tower::Service;
+
+        let (state, _mock) = mock_state(vec![bulb("B0:CE:18:00:00:01", "0")]).await;
+
+        let request = Request::builder()
+            .uri("/metrics")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = crate::app(&state).call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_type = response.headers()["Content-Type"].to_str().unwrap();
+        assert!(content_type.starts_with("text/plain"));
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        for (name, _, _) in super::METRICS {
+            assert!(body.contains(&format!("# HELP {} ", name)));
+            assert!(body.contains(&format!("# TYPE {} ", name)));
+        }
+
+        let mut samples = 0;
+        for line in body.lines().filter(|line| !line.starts_with('#')) {
+            let (series, value) = line.split_once(' ').unwrap();
+            let name = series.strip_suffix("{account=\"default\"}").unwrap();
+            assert!(super::METRICS.iter().any(|(metric, _, _)| *metric == name));
+            value.parse::<u64>().unwrap();
+            samples += 1;
+        }
+        assert_eq!(samples, super::METRICS.len());
+        assert!(body.contains("sengled_devices{account=\"default\"} 1\n"));
+    }
 }
@@ -0,0 +1,253 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{routes::lookup_device, Account, AppState, DeviceUpdate};
+
+// a control message sent by a websocket client. the optional id is echoed back in the result
+// so that clients can match results to the messages they sent
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    SetAttributes {
+        id: Option<u64>,
+        device: String,
+        attributes: HashMap<String, String>,
+    },
+    Toggle {
+        id: Option<u64>,
+        device: String,
+    },
+}
+
+// a message sent to a websocket client. every connection starts with a snapshot of the cache,
+// and another is sent if the client falls behind, so clients never need to track missed updates
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Snapshot {
+        devices: Vec<sengled::Device>,
+    },
+    Update(DeviceUpdate),
+    Result {
+        id: Option<u64>,
+        error: Option<String>,
+    },
+}
+
+impl ServerMessage {
+    fn snapshot(account: &Account) -> Self {
+        Self::Snapshot {
+            devices: account
+                .devices
+                .iter()
+                .map(|pair| pair.value().to_owned())
+                .collect(),
+        }
+    }
+}
+
+pub(crate) async fn upgrade(
+    State(state): State<Arc<AppState>>,
+    State(account): State<Arc<Account>>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| handle_socket(state, account, socket))
+}
+
+async fn handle_socket(state: Arc<AppState>, account: Arc<Account>, mut socket: WebSocket) {
+    let mut updates = state.updates.subscribe();
+
+    if send(&mut socket, &ServerMessage::snapshot(&account))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    loop {
+        let message = tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) if update.account != account.id => continue,
+                Ok(update) => ServerMessage::Update(update),
+                Err(RecvError::Lagged(_)) => ServerMessage::snapshot(&account),
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => handle_message(&account, &text).await,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            _ = state.shutting_down() => break,
+        };
+
+        if send(&mut socket, &message).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> Result<(), axum::Error> {
+    socket
+        .send(Message::Text(serde_json::to_string(message).unwrap()))
+        .await
+}
+
+async fn handle_message(account: &Account, text: &str) -> ServerMessage {
+    let (id, result) = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(ClientMessage::SetAttributes {
+            id,
+            device,
+            attributes,
+        }) => (id, set_attributes(account, &device, &attributes).await),
+        Ok(ClientMessage::Toggle { id, device }) => (id, toggle(account, &device).await),
+        Err(error) => (None, Err(format!("invalid message: {}", error))),
+    };
+
+    ServerMessage::Result {
+        id,
+        error: result.err(),
+    }
+}
+
+async fn set_attributes(
+    account: &Account,
+    device: &str,
+    attributes: &HashMap<String, String>,
+) -> Result<(), String> {
+    // send to the MAC in the device's own form, since topics are case-sensitive
+    let mac = &lookup_device(account, device)
+        .map_err(|error| error.to_string())?
+        .mac
+        .to_owned();
+
+    let pairs = attributes.iter().collect::<Vec<_>>();
+    account
+        .with_client(|client| {
+            let pairs = &pairs;
+            async move { client.set_device_attributes(mac, pairs).await }
+        })
+        .await
+        .map_err(|error| error.to_string())
+}
+
+async fn toggle(account: &Account, device: &str) -> Result<(), String> {
+    let (mac, switch) = {
+        let cached = lookup_device(account, device).map_err(|error| error.to_string())?;
+        let switch = cached
+            .is_on()
+            .ok_or_else(|| format!("device {} has no switch state", device))?;
+        (cached.mac.to_owned(), switch)
+    };
+
+    let mac = &mac;
+    account
+        .with_client(|client| async move { client.toggle_device(mac, switch).await })
+        .await
+        .map(|_| ())
+        .map_err(|error| error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Request, StatusCode};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::tests::{bulb, mock_state, send};
+
+    // handle a control message, returning the result sent back as JSON
+    async fn round_trip(account: &Account, message: Value) -> Value {
+        let result = handle_message(account, &message.to_string()).await;
+        serde_json::to_value(result).unwrap()
+    }
+
+    #[tokio::test]
+    async fn control_messages_publish_and_echo_their_id() {
+        let (state, mock) = mock_state(vec![bulb("B0:CE:18:00:00:01", "0")]).await;
+        let account = &state.accounts[0];
+
+        let result = round_trip(
+            account,
+            json!({
+                "type": "set_attributes",
+                "id": 1,
+                "device": "b0:ce:18:00:00:01",
+                "attributes": { "brightness": "40" },
+            }),
+        )
+        .await;
+        assert_eq!(result, json!({ "type": "result", "id": 1, "error": null }));
+
+        let result = round_trip(
+            account,
+            json!({ "type": "toggle", "id": 2, "device": "B0:CE:18:00:00:01" }),
+        )
+        .await;
+        assert_eq!(result, json!({ "type": "result", "id": 2, "error": null }));
+
+        let published = mock.published();
+        assert_eq!(published.len(), 2);
+        assert!(published
+            .iter()
+            .all(|(topic, _)| topic == "wifielement/B0:CE:18:00:00:01/update"));
+    }
+
+    #[tokio::test]
+    async fn bad_control_messages_report_errors() {
+        let (state, mock) = mock_state(Vec::new()).await;
+        let account = &state.accounts[0];
+
+        let result = round_trip(
+            account,
+            json!({ "type": "toggle", "id": 3, "device": "B0:CE:18:00:00:01" }),
+        )
+        .await;
+        assert_eq!(result["id"], 3);
+        assert!(result["error"].is_string());
+
+        let result = round_trip(account, json!({ "type": "dance" })).await;
+        assert_eq!(result["id"], Value::Null);
+        assert!(result["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid message"));
+
+        assert!(mock.published().is_empty());
+    }
+
+    #[test]
+    fn updates_are_framed_with_their_type() {
+        let update = ServerMessage::Update(DeviceUpdate {
+            account: String::from("default"),
+            device: String::from("B0:CE:18:00:00:01"),
+            attributes: HashMap::from([(String::from("switch"), String::from("1"))]),
+        });
+
+        assert_eq!(
+            serde_json::to_value(update).unwrap(),
+            json!({
+                "type": "update",
+                "account": "default",
+                "device": "B0:CE:18:00:00:01",
+                "attributes": { "switch": "1" },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn upgrading_requires_the_auth_key() {
+        let (state, _mock) = mock_state(Vec::new()).await;
+
+        let (status, _) = send(&state, Request::builder().uri("/ws"), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,24 @@
+#[tokio::main]
+async fn main() {
+    // log in and start in one step
+    let mut client = sengled::Client::new("username", "password");
+    let event_handler = client.login_and_start().await.unwrap();
+
+    // we must spawn the event handler listener for the API to function, or handle the events ourselves
+    event_handler.spawn_listener(&mut client);
+
+    // print every device with its attributes
+    for device in client.wifi_devices().await.unwrap() {
+        println!("{} ({})", device.display_name(), device.mac);
+
+        for attribute in device.known_attributes() {
+            println!(
+                "  {}: {}",
+                attribute,
+                device.get_attribute_or(attribute, "")
+            );
+        }
+    }
+
+    client.close().await.unwrap();
+}
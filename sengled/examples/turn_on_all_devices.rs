@@ -13,12 +13,9 @@ async fn main() {
     // get wifi devices
     let devices = client.wifi_devices().await.unwrap();
 
-    // turn all of them on by setting "switch" to "1"
+    // turn all of them on
     for device in devices {
-        client
-            .set_device_attribute(device, "switch", "1")
-            .await
-            .unwrap();
+        client.turn_on(device).await.unwrap();
     }
 
     // close the client, ensuring MQTT messages are actually sent
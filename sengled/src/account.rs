@@ -0,0 +1,67 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// Details about the account a session belongs to. Every field is optional, since the user
+/// info response is undocumented and varies between accounts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AccountInfo {
+    #[serde(
+        default,
+        alias = "userId",
+        alias = "customerId",
+        deserialize_with = "deserialize_user_id"
+    )]
+    pub user_id: Option<String>,
+
+    #[serde(default, alias = "user", alias = "userName", alias = "account")]
+    pub username: Option<String>,
+
+    #[serde(default, alias = "mail")]
+    pub email: Option<String>,
+
+    #[serde(default, alias = "nickName")]
+    pub nickname: Option<String>,
+}
+
+/// User ids are sent either as strings or as numbers.
+fn deserialize_user_id<'de, D: Deserializer<'de>>(
+    deserialize: D,
+) -> Result<Option<String>, D::Error> {
+    Ok(match Value::deserialize(deserialize)? {
+        Value::String(id) => Some(id),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn account_info_tolerates_schema_drift() {
+        let info: AccountInfo = serde_json::from_value(serde_json::json!({
+            "customerId": 123456,
+            "user": "user@example.com",
+            "nickName": "Home",
+            "extra": { "ignored": true },
+        }))
+        .unwrap();
+        assert_eq!(info.user_id.as_deref(), Some("123456"));
+        assert_eq!(info.username.as_deref(), Some("user@example.com"));
+        assert_eq!(info.nickname.as_deref(), Some("Home"));
+        assert_eq!(info.email, None);
+
+        let info: AccountInfo = serde_json::from_value(serde_json::json!({
+            "userId": "abc",
+            "email": "user@example.com",
+        }))
+        .unwrap();
+        assert_eq!(info.user_id.as_deref(), Some("abc"));
+        assert_eq!(info.email.as_deref(), Some("user@example.com"));
+
+        let info: AccountInfo = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert_eq!(info.user_id, None);
+        assert_eq!(info.username, None);
+    }
+}
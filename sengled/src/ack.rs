@@ -0,0 +1,231 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use rumqttc::{AsyncClient as MqttClient, Event as MqttEvent, Incoming, Outgoing, QoS};
+use tokio::sync::oneshot;
+
+use crate::{metrics::Counters, rate_limit::RateLimiter, Error};
+
+/// Matches outgoing publishes to the acks the broker sends back for them.
+///
+/// rumqttc assigns packet ids when a publish leaves the event loop, in the order publishes
+/// were queued. Publishes are queued under `send_lock` so that the order of `queued` is the
+/// order the event loop will see them in.
+///
+/// Every publish goes through here, so this is also where the outbound rate limit applies,
+/// where publishes are counted and where they are skipped in a dry run.
+#[derive(Default)]
+pub(crate) struct AckTracker {
+    send_lock: tokio::sync::Mutex<()>,
+    state: Mutex<AckState>,
+    rate_limit: Option<RateLimiter>,
+    dry_run: bool,
+    counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct AckState {
+    /// Publishes queued on the client that the event loop has not sent yet.
+    queued: VecDeque<Queued>,
+
+    /// Publishes that have been sent and are waiting on an ack, by packet id.
+    inflight: HashMap<u16, Option<oneshot::Sender<Result<(), Error>>>>,
+}
+
+/// Who to notify once a queued publish is sent and acknowledged.
+#[derive(Default)]
+pub(crate) struct Queued {
+    /// Notified with the packet id once the event loop sends the publish.
+    pub(crate) sent: Option<oneshot::Sender<Result<u16, Error>>>,
+
+    /// Notified once the broker acknowledges the publish.
+    pub(crate) ack: Option<oneshot::Sender<Result<(), Error>>>,
+}
+
+impl AckTracker {
+    /// Create a tracker that allows at most `max_per_second` publishes, if given, and counts
+    /// them in `counters`. In a dry run, publishes are logged instead of sent.
+    pub(crate) fn new(max_per_second: Option<u32>, dry_run: bool, counters: Arc<Counters>) -> Self {
+        Self {
+            rate_limit: max_per_second.map(RateLimiter::new),
+            dry_run,
+            counters,
+            ..Self::default()
+        }
+    }
+
+    /// Queue a publish, notifying `queued` as it is sent and acknowledged. Publishes at
+    /// `QoS::AtMostOnce` have no packet id and are never acknowledged.
+    pub(crate) async fn publish(
+        &self,
+        mqtt: &MqttClient,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        queued: Queued,
+    ) -> Result<(), Error> {
+        if self.dry_run {
+            #[cfg(feature = "tracing")]
+            tracing::info!(
+                topic,
+                payload = %String::from_utf8_lossy(&payload),
+                "dry run, skipping publish"
+            );
+
+            // report the publish as acknowledged, since nothing will ever ack it
+            if let Some(ack) = queued.ack {
+                let _ = ack.send(Ok(()));
+            }
+            return Ok(());
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.acquire().await;
+        }
+
+        if qos == QoS::AtMostOnce {
+            if let Err(error) = mqtt.publish(topic, qos, retain, payload).await {
+                Counters::increment(&self.counters.errors);
+                return Err(error.into());
+            }
+
+            Counters::increment(&self.counters.publishes_sent);
+            return Ok(());
+        }
+
+        let _guard = self.send_lock.lock().await;
+        self.state.lock().unwrap().queued.push_back(queued);
+
+        if let Err(error) = mqtt.publish(topic, qos, retain, payload).await {
+            self.state.lock().unwrap().queued.pop_back();
+            Counters::increment(&self.counters.errors);
+            return Err(error.into());
+        }
+
+        Counters::increment(&self.counters.publishes_sent);
+        Ok(())
+    }
+
+    /// Update the tracker with an event from the event loop.
+    pub(crate) fn observe(&self, event: &MqttEvent) {
+        let mut state = self.state.lock().unwrap();
+
+        match event {
+            // publishes are retransmitted with the same packet id after a reconnect
+            MqttEvent::Outgoing(Outgoing::Publish(pkid))
+                if *pkid != 0 && !state.inflight.contains_key(pkid) =>
+            {
+                let (sent, ack) = match state.queued.pop_front() {
+                    Some(Queued { sent, ack }) => (sent, ack),
+                    None => (None, None),
+                };
+
+                if let Some(sent) = sent {
+                    let _ = sent.send(Ok(*pkid));
+                }
+                state.inflight.insert(*pkid, ack);
+            }
+            MqttEvent::Incoming(Incoming::PubAck(rumqttc::PubAck { pkid }))
+            | MqttEvent::Incoming(Incoming::PubComp(rumqttc::PubComp { pkid })) => {
+                if let Some(Some(ack)) = state.inflight.remove(pkid) {
+                    let _ = ack.send(Ok(()));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Fail every publish still waiting to be sent or acknowledged with
+    /// `Error::ConnectionFailure`, once the connection it was made on is gone. The event loop
+    /// resends these publishes after reconnecting, so their entries are kept, with no one left
+    /// to notify, so that acks for the resent publishes are not matched to later ones.
+    pub(crate) fn fail_pending(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        for queued in &mut state.queued {
+            if let Some(sent) = queued.sent.take() {
+                let _ = sent.send(Err(Error::ConnectionFailure));
+            }
+            if let Some(ack) = queued.ack.take() {
+                let _ = ack.send(Err(Error::ConnectionFailure));
+            }
+        }
+
+        for ack in state.inflight.values_mut().filter_map(Option::take) {
+            let _ = ack.send(Err(Error::ConnectionFailure));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::PubAck;
+
+    use super::*;
+
+    fn queue(tracker: &AckTracker) -> oneshot::Receiver<Result<(), Error>> {
+        let (ack, acked) = oneshot::channel();
+        tracker.state.lock().unwrap().queued.push_back(Queued {
+            sent: None,
+            ack: Some(ack),
+        });
+        acked
+    }
+
+    fn sent(pkid: u16) -> MqttEvent {
+        MqttEvent::Outgoing(Outgoing::Publish(pkid))
+    }
+
+    fn acked(pkid: u16) -> MqttEvent {
+        MqttEvent::Incoming(Incoming::PubAck(PubAck::new(pkid)))
+    }
+
+    #[test]
+    fn acks_resolve_their_own_publish() {
+        let tracker = AckTracker::default();
+        let mut first = queue(&tracker);
+        let mut second = queue(&tracker);
+
+        tracker.observe(&sent(1));
+        tracker.observe(&sent(2));
+        tracker.observe(&acked(2));
+        assert!(matches!(second.try_recv(), Ok(Ok(()))));
+        assert!(first.try_recv().is_err());
+
+        tracker.observe(&acked(1));
+        assert!(matches!(first.try_recv(), Ok(Ok(()))));
+    }
+
+    #[test]
+    fn pending_publishes_fail_on_reconnect() {
+        let tracker = AckTracker::default();
+        let mut inflight = queue(&tracker);
+        tracker.observe(&sent(1));
+        let mut unsent = queue(&tracker);
+
+        tracker.fail_pending();
+        assert!(matches!(
+            inflight.try_recv(),
+            Ok(Err(Error::ConnectionFailure))
+        ));
+        assert!(matches!(
+            unsent.try_recv(),
+            Ok(Err(Error::ConnectionFailure))
+        ));
+
+        // the event loop resends both after reconnecting, then sends the next publish
+        let mut next = queue(&tracker);
+        tracker.observe(&sent(1));
+        tracker.observe(&sent(2));
+        tracker.observe(&sent(3));
+        tracker.observe(&acked(1));
+        tracker.observe(&acked(2));
+        assert!(next.try_recv().is_err());
+
+        tracker.observe(&acked(3));
+        assert!(matches!(next.try_recv(), Ok(Ok(()))));
+    }
+}
@@ -0,0 +1,257 @@
+use std::time::Duration;
+
+use rumqttc::{LastWill, QoS};
+
+#[cfg(feature = "proxy")]
+use crate::MqttProxy;
+use crate::{
+    build_http_client, connection::MqttServer, debounce::Debouncer, validate_keep_alive, Client,
+    Error, Region, DEFAULT_CONNECT_TIMEOUT, DEFAULT_KEEP_ALIVE, DEFAULT_MQTT_CAPACITY,
+};
+
+/// A builder for a `Client`, which validates the combination of options up front.
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// let client = sengled::Client::builder("username", "password")
+///     .region(sengled::Region::Eu)
+///     .http_timeout(Duration::from_secs(10))
+///     .build()
+///     .unwrap();
+/// ```
+pub struct ClientBuilder {
+    username: String,
+    password: String,
+    preferred_qos: QoS,
+    skip_server_check: bool,
+    http_timeout: Option<Duration>,
+    http_client: Option<reqwest::Client>,
+    proxy: Option<reqwest::Proxy>,
+    http_retries: u32,
+    http_backoff: Duration,
+    #[cfg(feature = "proxy")]
+    mqtt_proxy: Option<MqttProxy>,
+    keep_alive: Duration,
+    keep_alive_jitter: Duration,
+    jitter_seed: Option<u64>,
+    connect_timeout: Duration,
+    mqtt_capacity: usize,
+    debounce: Option<Duration>,
+    rate_limit: Option<u32>,
+    strict_attributes: bool,
+    dry_run: bool,
+    last_will: Option<LastWill>,
+    region: Option<Region>,
+    server_url: Option<String>,
+}
+
+impl ClientBuilder {
+    pub(crate) fn new(username: &str, password: &str) -> Self {
+        Self {
+            username: String::from(username),
+            password: String::from(password),
+            preferred_qos: QoS::AtMostOnce,
+            skip_server_check: false,
+            http_timeout: None,
+            http_client: None,
+            proxy: None,
+            http_retries: 0,
+            http_backoff: Duration::ZERO,
+            #[cfg(feature = "proxy")]
+            mqtt_proxy: None,
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            keep_alive_jitter: Duration::ZERO,
+            jitter_seed: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            mqtt_capacity: DEFAULT_MQTT_CAPACITY,
+            debounce: None,
+            rate_limit: None,
+            strict_attributes: false,
+            dry_run: false,
+            last_will: None,
+            region: None,
+            server_url: None,
+        }
+    }
+
+    /// See `Client::with_preferred_qos`.
+    pub fn preferred_qos(mut self, qos: QoS) -> Self {
+        self.preferred_qos = qos;
+        self
+    }
+
+    /// See `Client::with_skip_server_check`. Cannot be combined with `server_url`.
+    pub fn skip_server_check(mut self) -> Self {
+        self.skip_server_check = true;
+        self
+    }
+
+    /// See `Client::with_http_timeout`.
+    pub fn http_timeout(mut self, timeout: Duration) -> Self {
+        self.http_timeout = Some(timeout);
+        self
+    }
+
+    /// See `Client::with_http_client`. Cannot be combined with `http_timeout`, since the given
+    /// client is used as-is.
+    pub fn http_client(mut self, http: reqwest::Client) -> Self {
+        self.http_client = Some(http);
+        self
+    }
+
+    /// See `Client::with_proxy`. Cannot be combined with `http_client`.
+    pub fn proxy(mut self, proxy: reqwest::Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// See `Client::with_http_retries`.
+    pub fn http_retries(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.http_retries = attempts;
+        self.http_backoff = backoff;
+        self
+    }
+
+    /// See `Client::with_mqtt_proxy`.
+    #[cfg(feature = "proxy")]
+    pub fn mqtt_proxy(mut self, proxy: MqttProxy) -> Self {
+        self.mqtt_proxy = Some(proxy);
+        self
+    }
+
+    /// See `Client::with_keep_alive`.
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    /// See `Client::with_keep_alive_jitter`.
+    pub fn keep_alive_jitter(mut self, max: Duration) -> Self {
+        self.keep_alive_jitter = max;
+        self
+    }
+
+    /// See `Client::with_jitter_seed`.
+    pub fn jitter_seed(mut self, seed: u64) -> Self {
+        self.jitter_seed = Some(seed);
+        self
+    }
+
+    /// See `Client::with_connect_timeout`.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// See `Client::with_mqtt_capacity`.
+    pub fn mqtt_capacity(mut self, capacity: usize) -> Self {
+        self.mqtt_capacity = capacity;
+        self
+    }
+
+    /// See `Client::with_debounce`.
+    pub fn debounce(mut self, window: Duration) -> Self {
+        self.debounce = Some(window);
+        self
+    }
+
+    /// See `Client::with_rate_limit`.
+    pub fn rate_limit(mut self, max_per_second: u32) -> Self {
+        self.rate_limit = Some(max_per_second);
+        self
+    }
+
+    /// See `Client::with_strict_attributes`.
+    pub fn strict_attributes(mut self) -> Self {
+        self.strict_attributes = true;
+        self
+    }
+
+    /// See `Client::with_dry_run`.
+    pub fn dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+
+    /// See `Client::with_last_will`.
+    pub fn last_will(mut self, topic: String, payload: Vec<u8>, qos: QoS, retain: bool) -> Self {
+        self.last_will = Some(LastWill::new(topic, payload, qos, retain));
+        self
+    }
+
+    /// See `Client::with_region`. Cannot be combined with `server_url`.
+    pub fn region(mut self, region: Region) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    /// See `Client::with_server_url`. Cannot be combined with `region` or `skip_server_check`,
+    /// since the given server is always used.
+    pub fn server_url(mut self, url: impl Into<String>) -> Self {
+        self.server_url = Some(url.into());
+        self
+    }
+
+    /// Build the client, returning `Error::IncompatibleOptions` if options conflict.
+    pub fn build(self) -> Result<Client, Error> {
+        if let Some(server_url) = &self.server_url {
+            if self.region.is_some() {
+                return Err(Error::IncompatibleOptions("server_url", "region"));
+            }
+
+            if self.skip_server_check {
+                return Err(Error::IncompatibleOptions(
+                    "server_url",
+                    "skip_server_check",
+                ));
+            }
+
+            MqttServer::parse(server_url)?;
+        }
+
+        if self.http_client.is_some() && self.http_timeout.is_some() {
+            return Err(Error::IncompatibleOptions("http_client", "http_timeout"));
+        }
+
+        if self.http_client.is_some() && self.proxy.is_some() {
+            return Err(Error::IncompatibleOptions("http_client", "proxy"));
+        }
+
+        validate_keep_alive(self.keep_alive)?;
+
+        let http = match self.http_client {
+            Some(http) => http,
+            None => build_http_client(self.http_timeout, self.proxy.clone())?,
+        };
+
+        Ok(Client {
+            http,
+            username: self.username,
+            password: self.password,
+            preferred_qos: self.preferred_qos,
+            region: self.region.unwrap_or_default(),
+            skip_server_check: self.skip_server_check,
+            server_url: self.server_url,
+            keep_alive: self.keep_alive,
+            keep_alive_jitter: self.keep_alive_jitter,
+            jitter_seed: self.jitter_seed,
+            connect_timeout: self.connect_timeout,
+            mqtt_capacity: self.mqtt_capacity,
+            debounce: self.debounce.map(Debouncer::new),
+            rate_limit: self.rate_limit,
+            strict_attributes: self.strict_attributes,
+            dry_run: self.dry_run,
+            last_will: self.last_will,
+            http_timeout: self.http_timeout,
+            http_proxy: self.proxy,
+            http_retries: self.http_retries,
+            http_backoff: self.http_backoff,
+            #[cfg(feature = "proxy")]
+            mqtt_proxy: self.mqtt_proxy,
+            #[cfg(feature = "mock")]
+            mock: None,
+            session: None,
+            state: None,
+        })
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use reqwest::Response;
+use rumqttc::{
+    ConnAck, ConnectReturnCode, ConnectionError, Event as MqttEvent, EventLoop, Incoming, LastWill,
+    MqttOptions, QoS, Transport,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::{Error, Region};
+
+/// How an `EventHandler` should wait between attempts to reconnect to the MQTT server.
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    /// The delay before the first reconnection attempt.
+    pub initial_delay: Duration,
+
+    /// The upper bound on the delay between attempts.
+    pub max_delay: Duration,
+
+    /// The factor the delay is multiplied by after each failed attempt.
+    pub multiplier: f64,
+
+    /// How much of each delay to add at random, so that many clients dropped at once don't
+    /// reconnect in lockstep. Each wait is drawn from `delay..delay * (1.0 + jitter)`, where
+    /// `jitter` is clamped to `0.0..=1.0`. Default is `0.0`, no jitter, so each wait is
+    /// exactly `delay`.
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.0,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Get the delay to wait after an attempt that waited `delay`.
+    pub(crate) fn next_delay(&self, delay: Duration) -> Duration {
+        delay.mul_f64(self.multiplier.max(1.0)).min(self.max_delay)
+    }
+
+    /// Get how long to actually wait for `delay`, with jitter applied.
+    pub(crate) fn jittered_delay(&self, delay: Duration, rng: &Jitter) -> Duration {
+        delay.mul_f64(1.0 + self.jitter.clamp(0.0, 1.0) * rng.fraction())
+    }
+}
+
+/// The random source for jitter, shared by everything built from one client so that a fixed
+/// seed makes the whole sequence reproducible.
+#[derive(Clone)]
+pub(crate) struct Jitter(Arc<Mutex<fastrand::Rng>>);
+
+impl Jitter {
+    pub(crate) fn new(seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => fastrand::Rng::with_seed(seed),
+            None => fastrand::Rng::new(),
+        };
+
+        Self(Arc::new(Mutex::new(rng)))
+    }
+
+    /// A random fraction in `0.0..1.0`.
+    fn fraction(&self) -> f64 {
+        self.0.lock().unwrap().f64()
+    }
+
+    /// A random duration in `0..max`, or zero if `max` is zero.
+    pub(crate) fn up_to(&self, max: Duration) -> Duration {
+        max.mul_f64(self.fraction())
+    }
+}
+
+/// The server info response, which names the MQTT server to connect to, see
+/// `Client::server_info`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerInfo {
+    /// The URL of the MQTT server.
+    #[serde(rename = "inceptionAddr")]
+    pub inception_addr: String,
+
+    /// Every other field of the response, which varies between regions and is undocumented.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+/// A MQTT server URL that was checked to have a host and the `wss` scheme, since the
+/// connection is always made over secure websockets.
+#[derive(Debug, Clone)]
+pub(crate) struct MqttServer {
+    pub(crate) url: Url,
+    host: String,
+}
+
+impl MqttServer {
+    /// Parse and check a server URL, returning `Error::InvalidServerUrl` if it cannot be
+    /// connected to.
+    pub(crate) fn parse(url: &str) -> Result<Self, Error> {
+        let url = Url::parse(url)?;
+        let invalid = |reason| Error::InvalidServerUrl {
+            url: url.to_string(),
+            reason,
+        };
+
+        if url.scheme() != "wss" {
+            return Err(invalid("the scheme must be `wss`"));
+        }
+
+        let host = match url.host_str() {
+            Some(host) if !host.is_empty() => host.to_owned(),
+            _ => return Err(invalid("the URL has no host")),
+        };
+
+        Ok(Self { url, host })
+    }
+}
+
+/// Everything needed to build the MQTT connection options, kept around so that the
+/// connection can be rebuilt when reconnecting.
+#[derive(Clone)]
+pub(crate) struct ConnectionConfig {
+    pub(crate) http: reqwest::Client,
+    /// Shared with the started client, so that reconnects use a session refreshed since.
+    pub(crate) session: Arc<RwLock<String>>,
+    pub(crate) region: Region,
+    pub(crate) skip_server_check: bool,
+    pub(crate) server_url: Option<MqttServer>,
+    pub(crate) keep_alive: Duration,
+    pub(crate) keep_alive_jitter: Duration,
+    pub(crate) jitter: Jitter,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) mqtt_capacity: usize,
+    pub(crate) rate_limit: Option<u32>,
+    pub(crate) dry_run: bool,
+    pub(crate) qos: QoS,
+    pub(crate) last_will: Option<LastWill>,
+    #[cfg(feature = "proxy")]
+    pub(crate) mqtt_proxy: Option<rumqttc::Proxy>,
+}
+
+impl ConnectionConfig {
+    async fn post<T: Serialize>(&self, url: &str, body: T) -> Result<Response, Error> {
+        let mut request = self
+            .http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header("Connection", "keep-alive")
+            .header("Cookie", format!("JSESSIONID={}", self.session()));
+
+        if let Some(host) = self.region.host_header() {
+            request = request.header("Host", host);
+        }
+
+        Ok(request.body(serde_json::to_string(&body)?).send().await?)
+    }
+
+    /// The current session, which `Client::relogin` may have replaced since `start`.
+    pub(crate) fn session(&self) -> String {
+        self.session.read().unwrap().to_owned()
+    }
+
+    /// Resolve the MQTT server, fetching it from the API unless a server was given or the
+    /// server check is skipped, in which case there is no server info.
+    pub(crate) async fn server(&self) -> Result<(MqttServer, Option<ServerInfo>), Error> {
+        const ROUTE: &str = "/life2/server/getServerInfo.json";
+
+        if let Some(server) = &self.server_url {
+            return Ok((server.clone(), None));
+        }
+
+        if self.skip_server_check {
+            return Ok((MqttServer::parse(self.region.mqtt_url())?, None));
+        }
+
+        let response = self
+            .post(&self.region.life2_route(ROUTE), json!({}))
+            .await?
+            .json::<ServerInfo>()
+            .await?;
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(server = %response.inception_addr, "fetched server info");
+
+        Ok((MqttServer::parse(&response.inception_addr)?, Some(response)))
+    }
+
+    /// Build the MQTT options for a fresh connection to the given server.
+    pub(crate) fn mqtt_options(&self, server: &MqttServer) -> MqttOptions {
+        let session = self.session();
+        let url = &server.url;
+        // rumqttc takes the websocket port from the address rather than the port given here, so
+        // a non-default port has to be kept in it
+        let address = match url.port() {
+            Some(port) => format!("wss://{}:{}{}", server.host, port, url.path()),
+            None => format!("wss://{}{}", server.host, url.path()),
+        };
+        let mut mqtt_options = MqttOptions::new(
+            format!("{}@lifeApp", session),
+            address,
+            url.port().unwrap_or(443),
+        );
+
+        let modifier_session = session;
+        mqtt_options
+            .set_transport(Transport::wss_with_default_config())
+            .set_keep_alive(self.jittered_keep_alive())
+            .set_request_modifier(move |mut request| {
+                let modifier_session = modifier_session.to_owned();
+
+                async move {
+                    let headers = request.headers_mut();
+                    headers.insert(
+                        "Cookie",
+                        format!("JSESSIONID={}", modifier_session).parse().unwrap(),
+                    );
+                    headers.insert("X-Requested-With", "com.sengled.life2".parse().unwrap());
+
+                    request
+                }
+            });
+
+        if let Some(last_will) = &self.last_will {
+            mqtt_options.set_last_will(last_will.clone());
+        }
+
+        #[cfg(feature = "proxy")]
+        if let Some(proxy) = &self.mqtt_proxy {
+            mqtt_options.set_proxy(proxy.clone());
+        }
+
+        mqtt_options
+    }
+
+    /// The keep-alive interval for a new connection, with a random offset of up to
+    /// `keep_alive_jitter` added. rumqttc only supports whole seconds.
+    fn jittered_keep_alive(&self) -> Duration {
+        let jittered = self.keep_alive + self.jitter.up_to(self.keep_alive_jitter);
+        Duration::from_secs(jittered.as_secs()).min(crate::MAX_KEEP_ALIVE)
+    }
+
+    /// Poll a fresh event loop until the server accepts the connection, giving up after the
+    /// connect timeout. A rejection is returned as `Error::ConnectionRefused` with the
+    /// server's reason.
+    pub(crate) async fn wait_for_connack(&self, events: &mut EventLoop) -> Result<(), Error> {
+        match tokio::time::timeout(self.connect_timeout, events.poll()).await {
+            Ok(Ok(MqttEvent::Incoming(Incoming::ConnAck(ConnAck {
+                code: ConnectReturnCode::Success,
+                ..
+            })))) => Ok(()),
+            Ok(Ok(MqttEvent::Incoming(Incoming::ConnAck(ConnAck { code, .. }))))
+            | Ok(Err(ConnectionError::ConnectionRefused(code))) => {
+                Err(Error::ConnectionRefused(code))
+            }
+            _ => Err(Error::ConnectionFailure),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(keep_alive_jitter: Duration, seed: u64) -> ConnectionConfig {
+        ConnectionConfig {
+            http: reqwest::Client::new(),
+            session: Arc::new(RwLock::new(String::from("session"))),
+            region: Region::Us,
+            skip_server_check: true,
+            server_url: None,
+            keep_alive: Duration::from_secs(30),
+            keep_alive_jitter,
+            jitter: Jitter::new(Some(seed)),
+            connect_timeout: Duration::from_secs(15),
+            mqtt_capacity: 10,
+            rate_limit: None,
+            dry_run: false,
+            qos: QoS::AtMostOnce,
+            last_will: None,
+            #[cfg(feature = "proxy")]
+            mqtt_proxy: None,
+        }
+    }
+
+    #[test]
+    fn jitter_is_deterministic_under_a_seed() {
+        let first = Jitter::new(Some(7));
+        let second = Jitter::new(Some(7));
+        for _ in 0..10 {
+            let max = Duration::from_secs(10);
+            assert_eq!(first.up_to(max), second.up_to(max));
+        }
+    }
+
+    #[test]
+    fn keep_alive_jitter_stays_in_range() {
+        let config = test_config(Duration::from_secs(10), 7);
+        for _ in 0..100 {
+            let keep_alive = config.jittered_keep_alive();
+            assert!(keep_alive >= Duration::from_secs(30), "{keep_alive:?}");
+            assert!(keep_alive <= Duration::from_secs(40), "{keep_alive:?}");
+        }
+
+        let config = test_config(Duration::ZERO, 7);
+        assert_eq!(config.jittered_keep_alive(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn reconnect_jitter_stays_in_range() {
+        let policy = ReconnectPolicy {
+            jitter: 0.5,
+            ..Default::default()
+        };
+        let rng = Jitter::new(Some(7));
+        let delay = Duration::from_secs(4);
+
+        for _ in 0..100 {
+            let jittered = policy.jittered_delay(delay, &rng);
+            assert!(jittered >= delay && jittered <= Duration::from_secs(6));
+        }
+
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.jittered_delay(delay, &rng), delay);
+    }
+
+    #[test]
+    fn reconnect_delays_grow_to_the_cap() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+
+        let delays = std::iter::successors(Some(policy.initial_delay), |delay| {
+            Some(policy.next_delay(*delay))
+        })
+        .take(6)
+        .map(|delay| delay.as_secs())
+        .collect::<Vec<_>>();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+
+        // a multiplier below 1 would shrink the delay, so it is treated as 1
+        let policy = ReconnectPolicy {
+            multiplier: 0.5,
+            ..policy
+        };
+        assert_eq!(
+            policy.next_delay(Duration::from_secs(3)),
+            Duration::from_secs(3)
+        );
+    }
+
+    #[test]
+    fn server_info_keeps_every_field() {
+        let info: ServerInfo = serde_json::from_value(json!({
+            "ret": 0,
+            "inceptionAddr": "wss://us-mqtt.cloud.sengled.com:443/mqtt",
+            "msg": "success",
+            "sessionTtl": 86400,
+        }))
+        .unwrap();
+
+        assert_eq!(
+            info.inception_addr,
+            "wss://us-mqtt.cloud.sengled.com:443/mqtt"
+        );
+        assert_eq!(info.extra.get("sessionTtl"), Some(&json!(86400)));
+        assert_eq!(info.extra.get("msg"), Some(&json!("success")));
+        assert!(!info.extra.contains_key("inceptionAddr"));
+    }
+
+    #[test]
+    fn server_urls_need_a_host_and_the_wss_scheme() {
+        let server = MqttServer::parse("wss://mqtt.example.com:8443/mqtt").unwrap();
+        assert_eq!(server.host, "mqtt.example.com");
+        assert_eq!(server.url.port(), Some(8443));
+
+        for url in [
+            "foo:bar",
+            "ws://mqtt.example.com/mqtt",
+            "http://mqtt.example.com",
+        ] {
+            assert!(
+                matches!(MqttServer::parse(url), Err(Error::InvalidServerUrl { .. })),
+                "{url}"
+            );
+        }
+
+        assert!(matches!(
+            MqttServer::parse("not a url"),
+            Err(Error::Parse(_))
+        ));
+    }
+}
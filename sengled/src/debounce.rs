@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rumqttc::{AsyncClient as MqttClient, QoS};
+
+use crate::{
+    ack::{AckTracker, Queued},
+    normalize_mac, update_topic,
+};
+
+/// Payloads waiting to be published with the MAC to publish them to, by normalized device MAC
+/// and attribute.
+type Pending = HashMap<(String, String), (String, String, QoS)>;
+
+/// Coalesces rapid attribute sets on the same device, publishing only the latest value once
+/// the window after the first set has passed.
+pub(crate) struct Debouncer {
+    window: Duration,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl Debouncer {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Queue a payload for an attribute, replacing any value still waiting for it. The first
+    /// payload for an attribute starts a timer that publishes whatever value is latest when
+    /// the window ends.
+    pub(crate) fn publish(
+        &self,
+        mqtt: &MqttClient,
+        acks: &Arc<AckTracker>,
+        mac: &str,
+        attribute: String,
+        payload: String,
+        qos: QoS,
+    ) {
+        let key = (normalize_mac(mac), attribute);
+        if self
+            .pending
+            .lock()
+            .unwrap()
+            .insert(key.clone(), (String::from(mac), payload, qos))
+            .is_some()
+        {
+            // a timer is already running for this attribute and will send the new value
+            return;
+        }
+
+        let window = self.window;
+        let pending = Arc::clone(&self.pending);
+        let mqtt = mqtt.clone();
+        let acks = Arc::clone(acks);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+
+            // the value was already sent if the client flushed while closing
+            let Some((mac, payload, qos)) = pending.lock().unwrap().remove(&key) else {
+                return;
+            };
+
+            let _result = send(&mqtt, &acks, &mac, payload, qos).await;
+
+            #[cfg(feature = "tracing")]
+            if let Err(error) = _result {
+                tracing::warn!(device = mac, %error, "failed to publish debounced attribute");
+            }
+        });
+    }
+
+    /// Publish every value still waiting for its window to end, such as before closing.
+    pub(crate) async fn flush(
+        &self,
+        mqtt: &MqttClient,
+        acks: &AckTracker,
+    ) -> Result<(), crate::Error> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+
+        for (mac, payload, qos) in pending.into_values() {
+            send(mqtt, acks, &mac, payload, qos).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn send(
+    mqtt: &MqttClient,
+    acks: &AckTracker,
+    mac: &str,
+    payload: String,
+    qos: QoS,
+) -> Result<(), crate::Error> {
+    acks.publish(
+        mqtt,
+        update_topic(mac),
+        qos,
+        false,
+        payload.into_bytes(),
+        Queued::default(),
+    )
+    .await
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use crate::Client;
+
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(100);
+
+    #[tokio::test]
+    async fn only_the_latest_value_is_sent_after_the_window() {
+        let (client, mock) = Client::new_mock();
+        let mut client = client.with_debounce(WINDOW);
+        let _event_handler = client.start().await.unwrap();
+
+        for value in ["10", "20", "30"] {
+            client
+                .set_device_attribute("B0:CE:18:00:00:01", "brightness", value)
+                .await
+                .unwrap();
+        }
+        client
+            .set_device_attribute("B0:CE:18:00:00:01", "switch", "1")
+            .await
+            .unwrap();
+        assert!(mock.published().is_empty());
+
+        tokio::time::sleep(WINDOW * 3).await;
+        let mut published = mock
+            .published()
+            .into_iter()
+            .map(|(_, payload)| payload)
+            .collect::<Vec<_>>();
+        published.sort_unstable();
+
+        assert_eq!(published.len(), 2);
+        assert!(published[0].contains(r#""type":"brightness","value":"30""#));
+        assert!(published[1].contains(r#""type":"switch","value":"1""#));
+    }
+
+    #[tokio::test]
+    async fn closing_sends_values_still_waiting() {
+        let (client, mock) = Client::new_mock();
+        let mut client = client.with_debounce(Duration::from_secs(60));
+        let _event_handler = client.start().await.unwrap();
+
+        client
+            .set_device_attribute("B0:CE:18:00:00:01", "brightness", "40")
+            .await
+            .unwrap();
+        assert!(mock.published().is_empty());
+
+        client.close_with_timeout(WINDOW).await.unwrap();
+        let published = mock.published();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].0, "wifielement/B0:CE:18:00:00:01/update");
+    }
+}
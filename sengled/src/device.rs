@@ -1,34 +1,493 @@
-use std::{collections::HashMap, fmt};
+use std::{
+    collections::HashMap,
+    fmt,
+    num::ParseIntError,
+    str::{FromStr, ParseBoolError},
+};
 
 use serde::{
-    de::{SeqAccess, Visitor},
+    de::{MapAccess, SeqAccess, Visitor},
     Deserialize, Deserializer, Serialize,
 };
 
+use serde_json::Value;
+
+use crate::{Client, Error};
+
+/// The warmest color temperature accepted by `Client::set_color_temperature_kelvin`.
+pub const MIN_COLOR_TEMPERATURE_KELVIN: u16 = 2000;
+
+/// The coolest color temperature accepted by `Client::set_color_temperature_kelvin`.
+pub const MAX_COLOR_TEMPERATURE_KELVIN: u16 = 7000;
+
+/// A well-known device attribute.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Attribute {
+    /// `switch`, whether the device is on (`1`) or off (`0`).
+    Switch,
+
+    /// `brightness`, the brightness of a bulb.
+    Brightness,
+
+    /// `color`, the RGB color of a bulb, as `r:g:b`.
+    Color,
+
+    /// `colorTemperature`, the color temperature of a bulb, as a 0-100 percentage.
+    ColorTemperature,
+
+    /// `colorMode`, the mode of a color bulb, see `ColorMode`.
+    ColorMode,
+
+    /// `online`, whether the device is reachable.
+    Online,
+
+    /// Any other attribute, by its name in the API.
+    Other(String),
+}
+
+impl Attribute {
+    /// Get the name of the attribute in the API.
+    pub fn as_wire_name(&self) -> &str {
+        match self {
+            Self::Switch => "switch",
+            Self::Brightness => "brightness",
+            Self::Color => "color",
+            Self::ColorTemperature => "colorTemperature",
+            Self::ColorMode => "colorMode",
+            Self::Online => "online",
+            Self::Other(name) => name,
+        }
+    }
+
+    /// Get the attribute from its name in the API.
+    pub fn from_wire_name(name: &str) -> Self {
+        match name {
+            "switch" => Self::Switch,
+            "brightness" => Self::Brightness,
+            "color" => Self::Color,
+            "colorTemperature" => Self::ColorTemperature,
+            "colorMode" => Self::ColorMode,
+            "online" => Self::Online,
+            _ => Self::Other(String::from(name)),
+        }
+    }
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_wire_name())
+    }
+}
+
+/// An RGB color, as stored in a device's `color` attribute.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Format the color the way the API expects it, as `r:g:b`.
+    pub(crate) fn to_attribute_value(self) -> String {
+        format!("{}:{}:{}", self.r, self.g, self.b)
+    }
+
+    /// Parse a color from an `r:g:b` attribute value.
+    pub(crate) fn from_attribute_value(value: &str) -> Option<Self> {
+        let mut channels = value.trim().split(':').map(|c| c.trim().parse::<u8>());
+
+        let color = Self {
+            r: channels.next()?.ok()?,
+            g: channels.next()?.ok()?,
+            b: channels.next()?.ok()?,
+        };
+
+        match channels.next() {
+            Some(_) => None,
+            None => Some(color),
+        }
+    }
+}
+
+/// How a device talks to the Sengled cloud.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceSource {
+    /// A device connected directly over WIFI, reporting on `wifielement/<mac>/status`.
+    Wifi,
+
+    /// A device behind a Sengled hub, reporting on `zigbee/<mac>/status`. This topic follows
+    /// the naming of the WIFI one and is unconfirmed.
+    Zigbee,
+}
+
+impl DeviceSource {
+    /// The first segment of the device's MQTT topics.
+    pub(crate) fn topic_prefix(&self) -> &'static str {
+        match self {
+            Self::Wifi => "wifielement",
+            Self::Zigbee => "zigbee",
+        }
+    }
+}
+
+/// The kind of a device, derived from its type code.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DeviceType {
+    /// A dimmable bulb with a fixed white color.
+    WhiteBulb,
+
+    /// A bulb with an adjustable color temperature.
+    TunableWhiteBulb,
+
+    /// A bulb with adjustable RGB color and color temperature.
+    ColorBulb,
+
+    /// A smart plug.
+    Plug,
+
+    /// A type code that is not known to this crate.
+    Unknown(String),
+}
+
+impl DeviceType {
+    /// Known type codes and the kind of device they belong to. Add new models here.
+    const KNOWN_TYPE_CODES: &'static [(&'static str, DeviceType)] = &[
+        ("W11-N13", DeviceType::WhiteBulb),
+        ("W21-N11", DeviceType::WhiteBulb),
+        ("W21-N13", DeviceType::WhiteBulb),
+        ("W12-N15", DeviceType::TunableWhiteBulb),
+        ("W31-N11", DeviceType::ColorBulb),
+        ("W31-N15", DeviceType::ColorBulb),
+        ("W41-N15A", DeviceType::ColorBulb),
+        ("W1A-N1", DeviceType::Plug),
+    ];
+
+    /// Get the kind of device from its type code.
+    pub fn from_type_code(type_code: &str) -> Self {
+        Self::KNOWN_TYPE_CODES
+            .iter()
+            .find(|(code, _)| code.eq_ignore_ascii_case(type_code))
+            .map(|(_, device_type)| device_type.clone())
+            .unwrap_or_else(|| Self::Unknown(String::from(type_code)))
+    }
+
+    /// Whether the device supports the `color` attribute.
+    pub fn supports_color(&self) -> bool {
+        matches!(self, Self::ColorBulb)
+    }
+
+    /// Whether the device supports the `colorTemperature` attribute.
+    pub fn supports_color_temperature(&self) -> bool {
+        matches!(self, Self::TunableWhiteBulb | Self::ColorBulb)
+    }
+
+    /// Whether the device supports the `brightness` attribute.
+    pub fn supports_brightness(&self) -> bool {
+        matches!(
+            self,
+            Self::WhiteBulb | Self::TunableWhiteBulb | Self::ColorBulb
+        )
+    }
+
+    /// The attributes that are safe to write for this kind of device. Read-only telemetry,
+    /// such as `online`, is never included. Unknown devices have no known writable attributes.
+    pub fn writable_attributes(&self) -> &'static [&'static str] {
+        match self {
+            Self::WhiteBulb => &["switch", "brightness"],
+            Self::TunableWhiteBulb => &["switch", "brightness", "colorTemperature"],
+            Self::ColorBulb => &[
+                "switch",
+                "brightness",
+                "colorTemperature",
+                "color",
+                "colorMode",
+            ],
+            Self::Plug => &["switch"],
+            Self::Unknown(_) => &[],
+        }
+    }
+
+    /// Whether the device is a smart plug.
+    pub fn is_plug(&self) -> bool {
+        matches!(self, Self::Plug)
+    }
+}
+
+impl fmt::Display for Color {
+    /// Formats the color as `#RRGGBB`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02X}{:02X}{:02X}", self.r, self.g, self.b)
+    }
+}
+
+impl FromStr for Color {
+    type Err = ParseColorError;
+
+    /// Parses either a hex color, `#RRGGBB` with or without the `#`, or comma-separated
+    /// decimal channels, `r,g,b`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if s.contains(',') {
+            let channels =
+                s.split(',')
+                    .map(|channel| {
+                        channel.trim().parse::<u8>().map_err(|_| {
+                            ParseColorError::InvalidChannel(String::from(channel.trim()))
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+            return match channels[..] {
+                [r, g, b] => Ok(Self { r, g, b }),
+                _ => Err(ParseColorError::ChannelCount(channels.len())),
+            };
+        }
+
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 {
+            return Err(ParseColorError::HexLength(hex.len()));
+        }
+
+        let channel = |range: std::ops::Range<usize>| {
+            hex.get(range)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| ParseColorError::InvalidHex(String::from(hex)))
+        };
+
+        Ok(Self {
+            r: channel(0..2)?,
+            g: channel(2..4)?,
+            b: channel(4..6)?,
+        })
+    }
+}
+
+/// An error parsing a `Color` from a string.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ParseColorError {
+    #[error("hex color must have 6 digits, got {0}")]
+    HexLength(usize),
+
+    #[error("invalid hex color: {0}")]
+    InvalidHex(String),
+
+    #[error("color must have 3 channels, got {0}")]
+    ChannelCount(usize),
+
+    #[error("invalid color channel: {0}")]
+    InvalidChannel(String),
+}
+
+/// The mode of a color bulb, stored in its numeric `colorMode` attribute.
+///
+/// The mapping assumed here is `1` for color, `2` for white and `3` for rhythm, which is what
+/// the multicolor WIFI bulbs use. Other values, such as from models that number their modes
+/// differently, are kept as `ColorMode::Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorMode {
+    /// The bulb shows its RGB `color`.
+    Color,
+
+    /// The bulb shows white at its `colorTemperature`.
+    White,
+
+    /// The bulb cycles colors with the beat of music.
+    Rhythm,
+
+    /// Any other mode, by its numeric value.
+    Other(u8),
+}
+
+impl ColorMode {
+    /// Get the numeric value of the mode in the API.
+    pub fn as_attribute_value(&self) -> u8 {
+        match self {
+            Self::Color => 1,
+            Self::White => 2,
+            Self::Rhythm => 3,
+            Self::Other(value) => *value,
+        }
+    }
+
+    /// Get the mode from its numeric value in the API.
+    pub fn from_attribute_value(value: u8) -> Self {
+        match value {
+            1 => Self::Color,
+            2 => Self::White,
+            3 => Self::Rhythm,
+            value => Self::Other(value),
+        }
+    }
+}
+
 /// A device provided by the Sengled API.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "RawDevice")]
 pub struct Device {
     pub category: String,
-
-    #[serde(rename(deserialize = "deviceUuid"))]
     pub mac: String,
-
-    #[serde(rename(deserialize = "typeCode"))]
     pub type_code: String,
 
+    /// The name of the device, as set in the Sengled app. The API reports it either as
+    /// `deviceName` or under the `name` attribute.
+    pub name: String,
+
+    /// The firmware version of the device, if reported. The API reports it either at the top
+    /// level or under the `version` attribute.
+    pub firmware_version: Option<String>,
+
+    /// The product code of the device, which identifies its model more precisely than the type
+    /// code, if reported. The API reports it either at the top level or under the
+    /// `productCode` attribute.
+    pub product_code: Option<String>,
+
+    pub attributes: HashMap<String, String>,
+}
+
+/// A device as it is laid out by the Sengled API. The aliases accept a `Device` as it is
+/// serialized, so that serialized devices can be read back.
+#[derive(Deserialize)]
+struct RawDevice {
+    category: String,
+
+    #[serde(rename = "deviceUuid", alias = "mac")]
+    mac: String,
+
+    #[serde(rename = "typeCode", alias = "type_code")]
+    type_code: String,
+
+    #[serde(default, rename = "deviceName", alias = "name")]
+    name: Option<String>,
+
+    #[serde(
+        default,
+        rename = "firmwareVersion",
+        alias = "version",
+        alias = "firmware_version"
+    )]
+    firmware_version: Option<String>,
+
+    #[serde(default, rename = "productCode", alias = "product_code")]
+    product_code: Option<String>,
+
     #[serde(
-        rename(deserialize = "attributeList"),
+        rename = "attributeList",
+        alias = "attributes",
         deserialize_with = "deserialize_attribute_list"
     )]
-    pub attributes: HashMap<String, String>,
+    attributes: HashMap<String, String>,
+}
+
+impl From<RawDevice> for Device {
+    fn from(raw: RawDevice) -> Self {
+        let name = raw
+            .name
+            .or_else(|| raw.attributes.get("name").cloned())
+            .unwrap_or_default();
+        let firmware_version = raw
+            .firmware_version
+            .or_else(|| raw.attributes.get("version").cloned());
+        let product_code = raw
+            .product_code
+            .or_else(|| raw.attributes.get("productCode").cloned());
+
+        Self {
+            category: raw.category,
+            mac: raw.mac,
+            type_code: raw.type_code,
+            name,
+            firmware_version,
+            product_code,
+            attributes: raw.attributes,
+        }
+    }
 }
 
 impl Device {
+    /// Get the kind of device, derived from its type code.
+    pub fn device_type(&self) -> DeviceType {
+        DeviceType::from_type_code(&self.type_code)
+    }
+
+    /// How the device talks to the Sengled cloud, from its category. Devices from
+    /// `Client::zigbee_devices` are behind a hub, others are WIFI devices.
+    pub fn source(&self) -> DeviceSource {
+        if self.category.eq_ignore_ascii_case("zigbee") {
+            DeviceSource::Zigbee
+        } else {
+            DeviceSource::Wifi
+        }
+    }
+
+    /// Get a label for the device: its name if it has one, otherwise its type code and the
+    /// last four digits of its MAC, such as `W31-N15 (A1B2)`.
+    pub fn display_name(&self) -> String {
+        let name = self.name.trim();
+        if !name.is_empty() {
+            return String::from(name);
+        }
+
+        let digits = normalize_mac(&self.mac).replace(':', "");
+        let suffix = match digits.char_indices().rev().nth(3) {
+            Some((index, _)) => &digits[index..],
+            None => &digits,
+        };
+        let type_code = match self.type_code.trim() {
+            "" => "Device",
+            type_code => type_code,
+        };
+
+        format!("{} ({})", type_code, suffix)
+    }
+
+    /// Get the keys of the attributes currently present on the device, sorted.
+    pub fn known_attributes(&self) -> Vec<&str> {
+        let mut keys = self
+            .attributes
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Iterate over the attributes currently present on the device as key-value pairs, in no
+    /// particular order.
+    pub fn attributes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.attributes
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
     /// Get an attribute on the device.
     pub fn get_attribute(&self, attribute: &str) -> Option<&str> {
         self.attributes.get(attribute).map(String::as_str)
     }
 
+    /// Parse an attribute on the device, ignoring surrounding whitespace. Returns `None` if the
+    /// attribute is missing, and the parse error if it is present but invalid.
+    pub fn get_attribute_as<T: FromStr>(&self, attribute: &str) -> Option<Result<T, T::Err>> {
+        self.get_attribute(attribute)
+            .map(|value| value.trim().parse())
+    }
+
+    /// Parse an attribute on the device as a `u8`, see `get_attribute_as`.
+    pub fn get_u8(&self, attribute: &str) -> Option<Result<u8, ParseIntError>> {
+        self.get_attribute_as(attribute)
+    }
+
+    /// Parse an attribute on the device as a boolean, accepting `1`/`true` and `0`/`false`.
+    /// Returns `None` if the attribute is missing.
+    pub fn get_bool(&self, attribute: &str) -> Option<Result<bool, ParseBoolError>> {
+        Some(parse_bool(self.get_attribute(attribute)?))
+    }
+
     /// Get an attribute on the device, or fallback to a default.
     pub fn get_attribute_or<'a>(&'a self, attribute: &str, default: &'a str) -> &'a str {
         self.attributes
@@ -36,6 +495,199 @@ impl Device {
             .map(String::as_str)
             .unwrap_or(default)
     }
+
+    /// Set an attribute on the device through the client, and update the local attribute
+    /// map to match what was sent.
+    pub async fn set_attribute(
+        &mut self,
+        client: &Client,
+        attribute: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), Error> {
+        let attribute = attribute.into();
+        let value = value.into();
+
+        client
+            .set_device_attribute(&*self, &attribute, &value)
+            .await?;
+        self.attributes.insert(attribute, value);
+
+        Ok(())
+    }
+
+    /// Get the brightness of the device. Stored values outside of `0..=255` saturate to the
+    /// nearest bound. Returns `None` if the attribute is missing or not a number.
+    pub fn brightness(&self) -> Option<u8> {
+        parse_brightness(self.get_attribute("brightness")?)
+    }
+
+    /// Get the approximate color temperature of the device in Kelvin, mapped back from the
+    /// stored `colorTemperature` percentage.
+    pub fn color_temperature_kelvin(&self) -> Option<u16> {
+        let percentage = self
+            .get_attribute("colorTemperature")?
+            .trim()
+            .parse::<u8>()
+            .ok()?;
+        color_temperature_to_kelvin(percentage)
+    }
+
+    /// Get the RGB color of the device. Returns `None` if the attribute is missing or malformed.
+    pub fn color(&self) -> Option<Color> {
+        Color::from_attribute_value(self.get_attribute("color")?)
+    }
+
+    /// Get the color mode of the device. Returns `None` if the attribute is missing or not a
+    /// number.
+    pub fn color_mode(&self) -> Option<ColorMode> {
+        let value = self.get_attribute("colorMode")?.trim().parse::<u8>().ok()?;
+        Some(ColorMode::from_attribute_value(value))
+    }
+
+    /// Whether the device is reachable, according to its `online` attribute.
+    pub fn is_online(&self) -> Option<bool> {
+        self.get_bool_attribute("online")
+    }
+
+    /// Whether the device is switched on, according to its `switch` attribute.
+    pub fn is_on(&self) -> Option<bool> {
+        self.get_bool_attribute("switch")
+    }
+
+    /// Get the power draw of a plug in watts, from its `power` attribute, which is assumed to
+    /// be in watts. Returns `None` if the attribute is missing, not a number, or negative.
+    pub fn power_watts(&self) -> Option<f64> {
+        self.get_meter_attribute("power")
+    }
+
+    /// Get the energy used by a plug in kilowatt-hours, from its `energy` attribute, which is
+    /// assumed to be in watt-hours. Returns `None` if the attribute is missing, not a number,
+    /// or negative.
+    pub fn energy_kwh(&self) -> Option<f64> {
+        Some(self.get_meter_attribute("energy")? / 1000.0)
+    }
+
+    /// Interpret an attribute as a meter reading, which must be a finite, non-negative number.
+    fn get_meter_attribute(&self, attribute: &str) -> Option<f64> {
+        let value = self.get_attribute_as::<f64>(attribute)?.ok()?;
+        (value.is_finite() && value >= 0.0).then_some(value)
+    }
+
+    /// Interpret an attribute as a boolean, ignoring values that are not a boolean.
+    fn get_bool_attribute(&self, attribute: &str) -> Option<bool> {
+        self.get_bool(attribute)?.ok()
+    }
+}
+
+/// An attribute change interpreted by its type, see `Event::typed_changes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypedChange {
+    /// `switch`, whether the device is on.
+    Switch(bool),
+
+    /// `brightness`, saturated to the `u8` range like `Device::brightness`.
+    Brightness(u8),
+
+    /// `color`, the RGB color of a bulb.
+    Color(Color),
+
+    /// `colorTemperature`, as a 0-100 percentage.
+    ColorTemperature(u8),
+
+    /// `colorMode`, the mode of a color bulb.
+    ColorMode(ColorMode),
+
+    /// `online`, whether the device is reachable.
+    Online(bool),
+
+    /// Any other attribute, or a known one whose value could not be interpreted.
+    Raw { attribute: String, value: String },
+}
+
+impl TypedChange {
+    /// Interpret a raw attribute change, falling back to `TypedChange::Raw`.
+    pub fn from_raw(attribute: &str, value: &str) -> Self {
+        let typed = match Attribute::from_wire_name(attribute) {
+            Attribute::Switch => parse_bool(value).ok().map(Self::Switch),
+            Attribute::Brightness => parse_brightness(value).map(Self::Brightness),
+            Attribute::Color => Color::from_attribute_value(value).map(Self::Color),
+            Attribute::ColorTemperature => value
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|percentage| *percentage <= 100)
+                .map(Self::ColorTemperature),
+            Attribute::ColorMode => value
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .map(|value| Self::ColorMode(ColorMode::from_attribute_value(value))),
+            Attribute::Online => parse_bool(value).ok().map(Self::Online),
+            Attribute::Other(_) => None,
+        };
+
+        typed.unwrap_or_else(|| Self::Raw {
+            attribute: String::from(attribute),
+            value: String::from(value),
+        })
+    }
+}
+
+/// Parse a boolean attribute value, accepting `1`/`true` and `0`/`false`.
+fn parse_bool(value: &str) -> Result<bool, ParseBoolError> {
+    match value.trim() {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        value => value.parse(),
+    }
+}
+
+/// Parse a `brightness` value, saturating values outside of `0..=255` to the nearest bound.
+fn parse_brightness(value: &str) -> Option<u8> {
+    let value = value.trim().parse::<i64>().ok()?;
+    Some(value.clamp(u8::MIN as i64, u8::MAX as i64) as u8)
+}
+
+/// Map a Kelvin value onto the 0-100 `colorTemperature` scale used by the API.
+pub(crate) fn kelvin_to_color_temperature(kelvin: u16) -> Result<u8, Error> {
+    if !(MIN_COLOR_TEMPERATURE_KELVIN..=MAX_COLOR_TEMPERATURE_KELVIN).contains(&kelvin) {
+        return Err(Error::OutOfRange {
+            value: kelvin as i64,
+            min: MIN_COLOR_TEMPERATURE_KELVIN as i64,
+            max: MAX_COLOR_TEMPERATURE_KELVIN as i64,
+        });
+    }
+
+    let span = (MAX_COLOR_TEMPERATURE_KELVIN - MIN_COLOR_TEMPERATURE_KELVIN) as u32;
+    let offset = (kelvin - MIN_COLOR_TEMPERATURE_KELVIN) as u32;
+    Ok(((offset * 100 + span / 2) / span) as u8)
+}
+
+/// Map a 0-100 `colorTemperature` value back to Kelvin.
+pub(crate) fn color_temperature_to_kelvin(percentage: u8) -> Option<u16> {
+    if percentage > 100 {
+        return None;
+    }
+
+    let span = (MAX_COLOR_TEMPERATURE_KELVIN - MIN_COLOR_TEMPERATURE_KELVIN) as u32;
+    Some(MIN_COLOR_TEMPERATURE_KELVIN + (percentage as u32 * span / 100) as u16)
+}
+
+/// Convert an attribute value to the string form used for attributes, since the API is not
+/// consistent about sending strings.
+fn stringify_attribute_value(value: Value) -> String {
+    match value {
+        Value::String(value) => value,
+        Value::Bool(value) => String::from(if value { "1" } else { "0" }),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+fn deserialize_attribute_value<'de, D: Deserializer<'de>>(
+    deserialize: D,
+) -> Result<String, D::Error> {
+    Ok(stringify_attribute_value(Value::deserialize(deserialize)?))
 }
 
 fn deserialize_attribute_list<'de, D: Deserializer<'de>>(
@@ -44,6 +696,8 @@ fn deserialize_attribute_list<'de, D: Deserializer<'de>>(
     #[derive(Deserialize)]
     struct Attribute {
         name: String,
+
+        #[serde(deserialize_with = "deserialize_attribute_value")]
         value: String,
     }
 
@@ -53,7 +707,10 @@ fn deserialize_attribute_list<'de, D: Deserializer<'de>>(
         type Value = HashMap<String, String>;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> std::fmt::Result {
-            write!(formatter, "an attribute, an object with a name and value")
+            write!(
+                formatter,
+                "a list of attributes, objects with a name and value, or a map of attributes"
+            )
         }
 
         fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
@@ -68,31 +725,124 @@ fn deserialize_attribute_list<'de, D: Deserializer<'de>>(
 
             Ok(map)
         }
+
+        fn visit_map<A>(self, mut access: A) -> Result<Self::Value, A::Error>
+        where
+            A: MapAccess<'de>,
+        {
+            let mut map = HashMap::new();
+
+            while let Some((name, value)) = access.next_entry::<String, Value>()? {
+                map.insert(name, stringify_attribute_value(value));
+            }
+
+            Ok(map)
+        }
     }
 
-    deserialize.deserialize_seq(AttributeVisitor)
+    deserialize.deserialize_any(AttributeVisitor)
+}
+
+/// Group devices by their category, such as `wifielement`, for listing them in sections.
+/// Devices keep the order they were given in.
+pub fn group_by_category(devices: &[Device]) -> HashMap<String, Vec<&Device>> {
+    let mut groups = HashMap::<String, Vec<&Device>>::new();
+    for device in devices {
+        groups
+            .entry(device.category.to_owned())
+            .or_default()
+            .push(device);
+    }
+
+    groups
+}
+
+/// Normalize a device MAC to the canonical form used throughout this crate, which is how the
+/// Sengled API lists WIFI devices: uppercase hex digit pairs separated by colons, such as
+/// `B0:CE:18:AA:BB:CC`. MACs written in lowercase, without separators or with dashes are
+/// converted. Identifiers that are not 6-byte MACs, such as Zigbee device ids, are only
+/// uppercased.
+pub fn normalize_mac(mac: &str) -> String {
+    let mac = mac.trim();
+    let digits = mac
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | '.'))
+        .collect::<String>();
+
+    if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return mac.to_ascii_uppercase();
+    }
+
+    digits
+        .to_ascii_uppercase()
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| std::str::from_utf8(pair).unwrap())
+        .collect::<Vec<_>>()
+        .join(":")
 }
 
 pub trait AsDeviceMac {
     fn as_device_mac(&self) -> &str;
+
+    /// The device's MAC in canonical form, see `normalize_mac`.
+    fn normalized_mac(&self) -> String {
+        normalize_mac(self.as_device_mac())
+    }
+
+    /// The device's type code, if known. Only a `Device` knows its type, so a plain MAC
+    /// returns `None`.
+    fn device_type_code(&self) -> Option<&str> {
+        None
+    }
+
+    /// How the device talks to the Sengled cloud, which decides the topic it reports on. Only
+    /// a `Device` knows this, so a plain MAC is taken to be a WIFI device.
+    fn device_source(&self) -> DeviceSource {
+        DeviceSource::Wifi
+    }
 }
 
 impl AsDeviceMac for Device {
     fn as_device_mac(&self) -> &str {
         &self.mac
     }
+
+    fn device_type_code(&self) -> Option<&str> {
+        Some(&self.type_code)
+    }
+
+    fn device_source(&self) -> DeviceSource {
+        self.source()
+    }
 }
 
 impl AsDeviceMac for &Device {
     fn as_device_mac(&self) -> &str {
         &self.mac
     }
+
+    fn device_type_code(&self) -> Option<&str> {
+        Some(&self.type_code)
+    }
+
+    fn device_source(&self) -> DeviceSource {
+        self.source()
+    }
 }
 
 impl AsDeviceMac for &mut Device {
     fn as_device_mac(&self) -> &str {
         &self.mac
     }
+
+    fn device_type_code(&self) -> Option<&str> {
+        Some(&self.type_code)
+    }
+
+    fn device_source(&self) -> DeviceSource {
+        self.source()
+    }
 }
 
 impl<T> AsDeviceMac for T
@@ -103,3 +853,476 @@ where
         self.as_ref()
     }
 }
+
+#[cfg(test)]
+impl Device {
+    /// A WIFI bulb with the given attributes, for tests.
+    pub(crate) fn test_bulb(mac: &str, attributes: &[(&str, &str)]) -> Self {
+        Self {
+            category: String::from("wifielement"),
+            mac: String::from(mac),
+            type_code: String::from("W21-N13"),
+            name: String::from("Bulb"),
+            firmware_version: None,
+            product_code: None,
+            attributes: attributes
+                .iter()
+                .map(|(name, value)| (String::from(*name), String::from(*value)))
+                .collect(),
+        }
+    }
+
+    /// A WIFI plug with the given attributes, for tests.
+    pub(crate) fn test_plug(mac: &str, attributes: &[(&str, &str)]) -> Self {
+        Self {
+            type_code: String::from("W1A-N1"),
+            name: String::from("Plug"),
+            ..Self::test_bulb(mac, attributes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brightness_saturates_out_of_range_values() {
+        let bulb = |value| Device::test_bulb("B0:CE:18:00:00:01", &[("brightness", value)]);
+
+        assert_eq!(bulb("128").brightness(), Some(128));
+        assert_eq!(bulb("300").brightness(), Some(255));
+        assert_eq!(bulb("-20").brightness(), Some(0));
+        assert_eq!(bulb("bright").brightness(), None);
+        assert_eq!(
+            Device::test_bulb("B0:CE:18:00:00:01", &[]).brightness(),
+            None
+        );
+    }
+
+    #[test]
+    fn kelvin_maps_endpoints_and_midpoint() {
+        assert_eq!(
+            kelvin_to_color_temperature(MIN_COLOR_TEMPERATURE_KELVIN).unwrap(),
+            0
+        );
+        assert_eq!(kelvin_to_color_temperature(4500).unwrap(), 50);
+        assert_eq!(
+            kelvin_to_color_temperature(MAX_COLOR_TEMPERATURE_KELVIN).unwrap(),
+            100
+        );
+
+        assert_eq!(
+            color_temperature_to_kelvin(0),
+            Some(MIN_COLOR_TEMPERATURE_KELVIN)
+        );
+        assert_eq!(color_temperature_to_kelvin(50), Some(4500));
+        assert_eq!(
+            color_temperature_to_kelvin(100),
+            Some(MAX_COLOR_TEMPERATURE_KELVIN)
+        );
+        assert_eq!(color_temperature_to_kelvin(101), None);
+    }
+
+    #[test]
+    fn kelvin_outside_the_band_is_an_error() {
+        assert!(matches!(
+            kelvin_to_color_temperature(1999),
+            Err(Error::OutOfRange { value: 1999, .. })
+        ));
+        assert!(matches!(
+            kelvin_to_color_temperature(7001),
+            Err(Error::OutOfRange { value: 7001, .. })
+        ));
+    }
+
+    #[test]
+    fn color_temperature_reads_back_as_kelvin() {
+        let bulb = Device::test_bulb("B0:CE:18:00:00:01", &[("colorTemperature", "50")]);
+        assert_eq!(bulb.color_temperature_kelvin(), Some(4500));
+    }
+
+    #[test]
+    fn color_round_trips_through_the_attribute() {
+        let color = Color::new(255, 128, 0);
+        let value = color.to_attribute_value();
+        assert_eq!(value, "255:128:0");
+        assert_eq!(Color::from_attribute_value(&value), Some(color));
+
+        let bulb = Device::test_bulb("B0:CE:18:00:00:01", &[("color", &value)]);
+        assert_eq!(bulb.color(), Some(color));
+    }
+
+    #[test]
+    fn malformed_colors_are_none() {
+        for value in [
+            "",
+            "255:128",
+            "255:128:0:1",
+            "256:0:0",
+            "red:green:blue",
+            "255,128,0",
+        ] {
+            assert_eq!(Color::from_attribute_value(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn online_and_switch_map_to_bools() {
+        for (value, expected) in [("1", true), ("true", true), ("0", false), ("false", false)] {
+            let bulb =
+                Device::test_bulb("B0:CE:18:00:00:01", &[("online", value), ("switch", value)]);
+            assert_eq!(bulb.is_online(), Some(expected), "{value}");
+            assert_eq!(bulb.is_on(), Some(expected), "{value}");
+        }
+
+        let bulb = Device::test_bulb("B0:CE:18:00:00:01", &[]);
+        assert_eq!(bulb.is_online(), None);
+        assert_eq!(bulb.is_on(), None);
+    }
+
+    #[test]
+    fn attribute_names_round_trip() {
+        for attribute in [
+            Attribute::Switch,
+            Attribute::Brightness,
+            Attribute::Color,
+            Attribute::ColorTemperature,
+            Attribute::ColorMode,
+            Attribute::Online,
+            Attribute::Other(String::from("consumptionTime")),
+        ] {
+            assert_eq!(
+                Attribute::from_wire_name(attribute.as_wire_name()),
+                attribute
+            );
+        }
+
+        assert_eq!(
+            Attribute::ColorTemperature.as_wire_name(),
+            "colorTemperature"
+        );
+        assert_eq!(Attribute::from_wire_name("switch"), Attribute::Switch);
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn set_attribute_updates_the_local_attributes() {
+        let (mut client, mock) = crate::Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+
+        let mut bulb = Device::test_bulb("B0:CE:18:00:00:01", &[("switch", "0")]);
+        bulb.set_attribute(&client, "switch", "1").await.unwrap();
+
+        assert_eq!(bulb.is_on(), Some(true));
+        assert_eq!(mock.published().len(), 1);
+    }
+
+    fn device_with_attributes(attributes: Value) -> Device {
+        serde_json::from_value(serde_json::json!({
+            "category": "wifielement",
+            "deviceUuid": "B0:CE:18:00:00:01",
+            "typeCode": "W21-N13",
+            "attributeList": attributes,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn attribute_lists_deserialize_from_arrays() {
+        let device = device_with_attributes(serde_json::json!([
+            { "name": "switch", "value": "1" },
+            { "name": "brightness", "value": "100" },
+        ]));
+        assert_eq!(device.get_attribute("switch"), Some("1"));
+        assert_eq!(device.get_attribute("brightness"), Some("100"));
+    }
+
+    #[test]
+    fn attribute_lists_deserialize_from_maps() {
+        let device = device_with_attributes(serde_json::json!({
+            "switch": "1",
+            "brightness": "100",
+        }));
+        assert_eq!(device.get_attribute("switch"), Some("1"));
+        assert_eq!(device.get_attribute("brightness"), Some("100"));
+    }
+
+    #[test]
+    fn numeric_and_bool_attribute_values_are_stringified() {
+        let device = device_with_attributes(serde_json::json!([
+            { "name": "brightness", "value": 100 },
+            { "name": "online", "value": true },
+        ]));
+        assert_eq!(device.get_attribute("brightness"), Some("100"));
+        assert_eq!(device.get_attribute("online"), Some("1"));
+
+        let device = device_with_attributes(serde_json::json!({
+            "brightness": 100,
+            "online": false,
+            "version": null,
+        }));
+        assert_eq!(device.get_attribute("brightness"), Some("100"));
+        assert_eq!(device.get_attribute("online"), Some("0"));
+        assert_eq!(device.get_attribute("version"), Some(""));
+    }
+
+    #[test]
+    fn attribute_values_of_each_type_are_stringified() {
+        use serde_json::json;
+
+        assert_eq!(stringify_attribute_value(json!("128")), "128");
+        assert_eq!(stringify_attribute_value(json!(128)), "128");
+        assert_eq!(stringify_attribute_value(json!(-3)), "-3");
+        assert_eq!(stringify_attribute_value(json!(0.5)), "0.5");
+        assert_eq!(stringify_attribute_value(json!(true)), "1");
+        assert_eq!(stringify_attribute_value(json!(false)), "0");
+        assert_eq!(stringify_attribute_value(json!(null)), "");
+    }
+
+    #[test]
+    fn names_deserialize_from_the_device_name_or_attribute() {
+        let device: Device = serde_json::from_value(serde_json::json!({
+            "category": "wifielement",
+            "deviceUuid": "B0:CE:18:00:00:01",
+            "typeCode": "W21-N13",
+            "deviceName": "Desk Lamp",
+            "attributeList": [],
+        }))
+        .unwrap();
+        assert_eq!(device.name, "Desk Lamp");
+
+        let device = device_with_attributes(serde_json::json!([
+            { "name": "name", "value": "Porch" },
+        ]));
+        assert_eq!(device.name, "Porch");
+    }
+
+    #[test]
+    fn bulbs_and_plugs_have_different_writable_attributes() {
+        let bulb = Device::test_bulb(
+            "B0:CE:18:00:00:01",
+            &[("switch", "1"), ("brightness", "100"), ("online", "1")],
+        );
+        assert_eq!(
+            bulb.known_attributes(),
+            vec!["brightness", "online", "switch"]
+        );
+        assert_eq!(
+            bulb.device_type().writable_attributes(),
+            &["switch", "brightness"]
+        );
+
+        let plug = DeviceType::from_type_code("W1A-N1");
+        assert_eq!(plug.writable_attributes(), &["switch"]);
+        assert!(!plug.writable_attributes().contains(&"online"));
+        assert!(DeviceType::from_type_code("X00")
+            .writable_attributes()
+            .is_empty());
+    }
+
+    #[test]
+    fn colors_parse_from_hex_and_decimal() {
+        let orange = Color::new(255, 128, 0);
+
+        assert_eq!("#FF8000".parse::<Color>(), Ok(orange));
+        assert_eq!("#ff8000".parse::<Color>(), Ok(orange));
+        assert_eq!("fF8000".parse::<Color>(), Ok(orange));
+        assert_eq!("255, 128, 0".parse::<Color>(), Ok(orange));
+        assert_eq!(orange.to_string(), "#FF8000");
+        assert_eq!(orange.to_string().parse::<Color>(), Ok(orange));
+    }
+
+    #[test]
+    fn invalid_colors_are_parse_errors() {
+        assert_eq!("#FF80".parse::<Color>(), Err(ParseColorError::HexLength(4)));
+        assert_eq!(
+            "FF800000".parse::<Color>(),
+            Err(ParseColorError::HexLength(8))
+        );
+        assert_eq!(
+            "#GG8000".parse::<Color>(),
+            Err(ParseColorError::InvalidHex(String::from("GG8000")))
+        );
+        assert_eq!(
+            "1,2".parse::<Color>(),
+            Err(ParseColorError::ChannelCount(2))
+        );
+        assert_eq!(
+            "1,2,300".parse::<Color>(),
+            Err(ParseColorError::InvalidChannel(String::from("300")))
+        );
+    }
+
+    #[test]
+    fn color_modes_round_trip() {
+        for mode in [
+            ColorMode::Color,
+            ColorMode::White,
+            ColorMode::Rhythm,
+            ColorMode::Other(7),
+        ] {
+            assert_eq!(
+                ColorMode::from_attribute_value(mode.as_attribute_value()),
+                mode
+            );
+        }
+
+        let bulb = Device::test_bulb("B0:CE:18:00:00:01", &[("colorMode", "2")]);
+        assert_eq!(bulb.color_mode(), Some(ColorMode::White));
+        let bulb = Device::test_bulb("B0:CE:18:00:00:01", &[("colorMode", "white")]);
+        assert_eq!(bulb.color_mode(), None);
+    }
+
+    #[test]
+    fn firmware_and_product_code_are_optional() {
+        let device: Device = serde_json::from_value(serde_json::json!({
+            "category": "wifielement",
+            "deviceUuid": "B0:CE:18:00:00:01",
+            "typeCode": "W21-N13",
+            "firmwareVersion": "V1.2.3",
+            "productCode": "wifielement",
+            "attributeList": [],
+        }))
+        .unwrap();
+        assert_eq!(device.firmware_version.as_deref(), Some("V1.2.3"));
+        assert_eq!(device.product_code.as_deref(), Some("wifielement"));
+
+        let device = device_with_attributes(serde_json::json!([
+            { "name": "version", "value": "V2.0.0" },
+        ]));
+        assert_eq!(device.firmware_version.as_deref(), Some("V2.0.0"));
+        assert_eq!(device.product_code, None);
+
+        let device = device_with_attributes(serde_json::json!([]));
+        assert_eq!(device.firmware_version, None);
+        assert_eq!(device.product_code, None);
+    }
+
+    #[test]
+    fn macs_normalize_to_uppercase_with_colons() {
+        for mac in [
+            "B0:CE:18:AA:BB:CC",
+            "b0:ce:18:aa:bb:cc",
+            "B0CE18AABBCC",
+            "b0-ce-18-aa-bb-cc",
+            "b0ce.18aa.bbcc",
+            "  b0:CE:18:aa:BB:cc ",
+        ] {
+            assert_eq!(normalize_mac(mac), "B0:CE:18:AA:BB:CC", "{mac}");
+        }
+
+        assert_eq!(normalize_mac("0017880100aa"), "00:17:88:01:00:AA");
+        assert_eq!(normalize_mac("zigbee-1a2b"), "ZIGBEE-1A2B");
+    }
+
+    #[test]
+    fn devices_compare_by_normalized_mac() {
+        let bulb = Device::test_bulb("b0:ce:18:aa:bb:cc", &[]);
+        assert_eq!(bulb.normalized_mac(), "B0CE18AABBCC".normalized_mac());
+        assert_eq!(bulb.as_device_mac(), "b0:ce:18:aa:bb:cc");
+    }
+
+    #[test]
+    fn attributes_yields_every_pair() {
+        let bulb = Device::test_bulb(
+            "B0:CE:18:00:00:01",
+            &[("switch", "1"), ("brightness", "100"), ("online", "1")],
+        );
+
+        let mut attributes = bulb.attributes().collect::<Vec<_>>();
+        attributes.sort_unstable();
+        assert_eq!(
+            attributes,
+            vec![("brightness", "100"), ("online", "1"), ("switch", "1")]
+        );
+    }
+
+    #[test]
+    fn typed_getters_parse_or_surface_errors() {
+        let bulb = Device::test_bulb(
+            "B0:CE:18:00:00:01",
+            &[
+                ("brightness", " 128 "),
+                ("colorTemperature", "300"),
+                ("switch", "1"),
+                ("online", "false"),
+                ("power", "4.5"),
+                ("name", "yes"),
+            ],
+        );
+
+        assert_eq!(bulb.get_u8("brightness"), Some(Ok(128)));
+        assert!(matches!(bulb.get_u8("colorTemperature"), Some(Err(_))));
+        assert_eq!(bulb.get_bool("switch"), Some(Ok(true)));
+        assert_eq!(bulb.get_bool("online"), Some(Ok(false)));
+        assert!(matches!(bulb.get_bool("name"), Some(Err(_))));
+        assert_eq!(bulb.get_attribute_as::<f64>("power"), Some(Ok(4.5)));
+        assert_eq!(bulb.get_attribute_as::<i32>("brightness"), Some(Ok(128)));
+
+        assert_eq!(bulb.get_u8("missing"), None);
+        assert_eq!(bulb.get_bool("missing"), None);
+    }
+
+    #[test]
+    fn plug_meters_parse_with_their_units() {
+        let plug = Device::test_plug(
+            "B0:CE:18:00:00:01",
+            &[("power", "12.5"), ("energy", "1500")],
+        );
+        assert!(plug.device_type().is_plug());
+        assert_eq!(plug.power_watts(), Some(12.5));
+        assert_eq!(plug.energy_kwh(), Some(1.5));
+
+        let plug = Device::test_plug("B0:CE:18:00:00:01", &[("power", "-1"), ("energy", "lots")]);
+        assert_eq!(plug.power_watts(), None);
+        assert_eq!(plug.energy_kwh(), None);
+
+        let plug = Device::test_plug("B0:CE:18:00:00:01", &[("power", "NaN")]);
+        assert_eq!(plug.power_watts(), None);
+        assert_eq!(plug.energy_kwh(), None);
+    }
+
+    #[test]
+    fn display_names_fall_back_to_the_type_and_mac() {
+        let mut bulb = Device::test_bulb("b0:ce:18:00:a1:b2", &[]);
+        bulb.name = String::from(" Desk Lamp ");
+        assert_eq!(bulb.display_name(), "Desk Lamp");
+
+        bulb.name = String::new();
+        assert_eq!(bulb.display_name(), "W21-N13 (A1B2)");
+        assert_eq!(bulb.display_name(), bulb.clone().display_name());
+
+        bulb.type_code = String::new();
+        bulb.mac = String::from("1a");
+        assert_eq!(bulb.display_name(), "Device (1A)");
+    }
+
+    #[test]
+    fn devices_are_grouped_by_category_in_order() {
+        let mut plug = Device::test_bulb("B0:CE:18:00:00:02", &[]);
+        plug.category = String::from("zigbee");
+        let devices = [
+            Device::test_bulb("B0:CE:18:00:00:01", &[]),
+            plug,
+            Device::test_bulb("B0:CE:18:00:00:03", &[]),
+        ];
+
+        let groups = group_by_category(&devices);
+        assert_eq!(groups.len(), 2);
+
+        let macs = |category: &str| {
+            groups[category]
+                .iter()
+                .map(|device| device.mac.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            macs("wifielement"),
+            ["B0:CE:18:00:00:01", "B0:CE:18:00:00:03"]
+        );
+        assert_eq!(macs("zigbee"), ["B0:CE:18:00:00:02"]);
+
+        assert!(group_by_category(&[]).is_empty());
+    }
+}
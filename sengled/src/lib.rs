@@ -1,19 +1,59 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
 
-use reqwest::Response;
+use futures::Stream;
+use reqwest::{Response, StatusCode};
 use rumqttc::{
-    AsyncClient as MqttClient, ConnAck, ConnectReturnCode, Event as MqttEvent, Incoming,
-    MqttOptions, SubscribeFilter, Transport,
+    AsyncClient as MqttClient, Event as MqttEvent, Incoming, LastWill, Outgoing, SubscribeFilter,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use thiserror::Error;
-use tokio::task::JoinHandle;
+use tokio::{sync::oneshot, task::JoinHandle};
 use url::Url;
 
+use ack::{AckTracker, Queued};
+use connection::MqttServer;
+use debounce::Debouncer;
+use metrics::Counters;
+
+mod account;
+mod ack;
+mod builder;
+mod connection;
+mod debounce;
 mod device;
+mod metrics;
+#[cfg(feature = "mock")]
+mod mock;
+mod rate_limit;
+mod region;
+mod room;
+pub use account::*;
+pub use builder::*;
+pub use connection::*;
 pub use device::*;
-pub use rumqttc::QoS;
+pub use metrics::Metrics;
+#[cfg(feature = "mock")]
+pub use mock::MockTransport;
+pub use region::*;
+pub use room::*;
+pub use rumqttc::{AsyncClient, ConnectReturnCode, QoS};
+#[cfg(feature = "proxy")]
+pub use rumqttc::{Proxy as MqttProxy, ProxyAuth as MqttProxyAuth, ProxyType as MqttProxyType};
+
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(30);
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_MQTT_CAPACITY: usize = 10;
+const MIN_KEEP_ALIVE: Duration = Duration::from_secs(5);
+const MAX_KEEP_ALIVE: Duration = Duration::from_secs(u16::MAX as u64);
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -32,11 +72,65 @@ pub enum Error {
     #[error("already logged in")]
     LoggedIn,
 
+    #[error("login failed ({code}): {message}")]
+    LoginFailed { code: i64, message: String },
+
+    #[error("API error ({code}): {message}")]
+    Api { code: i64, message: String },
+
     #[error("disconnected")]
     Disconnected,
 
+    #[error("client has not been started")]
+    NotStarted,
+
+    #[error("session has not been set, use `login` or `set_session`")]
+    NoSession,
+
+    /// The API rejected the session, usually because it expired. Log in again with
+    /// `relogin`.
+    #[error("session was rejected by the API")]
+    SessionExpired,
+
+    #[error("device not found: {0}")]
+    DeviceNotFound(String),
+
+    #[error("unexpected payload on topic {topic}: {snippet}")]
+    Payload { topic: String, snippet: String },
+
+    #[error("timed out waiting for the broker to acknowledge a message")]
+    AckTimeout,
+
+    #[error("timed out waiting for the device to report the attribute")]
+    AttributeTimeout,
+
+    #[error("timed out waiting for the listener to shut down")]
+    ShutdownTimeout,
+
     #[error("failed to connect to the MQTT server")]
     ConnectionFailure,
+
+    /// The MQTT server rejected the connection, such as for an expired session.
+    #[error("MQTT server refused the connection: {0:?}")]
+    ConnectionRefused(ConnectReturnCode),
+
+    /// The MQTT server URL, given with `with_server_url` or fetched from the API, cannot be
+    /// connected to, such as for a URL without a host or with a scheme other than `wss`.
+    #[error("invalid MQTT server URL `{url}`: {reason}")]
+    InvalidServerUrl { url: String, reason: &'static str },
+
+    #[error("option `{0}` cannot be combined with `{1}`")]
+    IncompatibleOptions(&'static str, &'static str),
+
+    #[error("value {value} is out of range ({min}..={max})")]
+    OutOfRange { value: i64, min: i64, max: i64 },
+
+    /// The attribute is not writable on the device's type, see `Client::with_strict_attributes`.
+    #[error("attribute `{attribute}` is not supported by {type_code} devices")]
+    UnsupportedAttribute {
+        attribute: String,
+        type_code: String,
+    },
 }
 
 pub struct Client {
@@ -44,7 +138,27 @@ pub struct Client {
     username: String,
     password: String,
     preferred_qos: QoS,
+    region: Region,
     skip_server_check: bool,
+    server_url: Option<String>,
+    keep_alive: Duration,
+    keep_alive_jitter: Duration,
+    jitter_seed: Option<u64>,
+    connect_timeout: Duration,
+    mqtt_capacity: usize,
+    debounce: Option<Debouncer>,
+    rate_limit: Option<u32>,
+    strict_attributes: bool,
+    dry_run: bool,
+    last_will: Option<LastWill>,
+    http_timeout: Option<Duration>,
+    http_proxy: Option<reqwest::Proxy>,
+    http_retries: u32,
+    http_backoff: Duration,
+    #[cfg(feature = "proxy")]
+    mqtt_proxy: Option<MqttProxy>,
+    #[cfg(feature = "mock")]
+    mock: Option<mock::MockBackend>,
     session: Option<String>,
     state: Option<ClientState>,
 }
@@ -52,98 +166,476 @@ pub struct Client {
 struct ClientState {
     mqtt: MqttClient,
     listener_handle: Option<JoinHandle<()>>,
+    subscriptions: Subscriptions,
+    server_url: Url,
+    server_info: Option<ServerInfo>,
+    acks: Arc<AckTracker>,
+    mqtt_connected: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+    session: Arc<RwLock<String>>,
+}
+
+/// Dropping a started client without `close` stops the spawned listener and queues a
+/// disconnect, but cannot wait for it to be sent. Prefer `close` for a clean disconnect.
+impl Drop for ClientState {
+    fn drop(&mut self) {
+        if let Some(listener_handle) = self.listener_handle.take() {
+            listener_handle.abort();
+        }
+
+        let _ = self.mqtt.try_disconnect();
+    }
 }
 
 pub enum Event {
+    /// The MQTT connection has been established. This is the first event returned by
+    /// `EventHandler::poll`.
+    Connected,
+
     DeviceAttributesChanged {
         device: String,
         attributes: Vec<(String, String)>,
+
+        /// Whether the device reported directly over WIFI or through a Zigbee hub.
+        source: DeviceSource,
+
+        /// When each attribute changed, in milliseconds since the epoch, keyed by attribute.
+        /// Attributes reported without a `time` are left out.
+        times: HashMap<String, i64>,
     },
+
+    /// The MQTT connection was lost. Only emitted when reconnecting automatically, in which
+    /// case the next poll reconnects and returns `Event::Reconnected`.
+    Disconnected,
+
+    /// The MQTT connection was lost and has been re-established. Device state may have
+    /// changed in the meantime.
+    Reconnected,
+
+    /// A publish on a topic that is not otherwise recognized. Only emitted when enabled with
+    /// `EventHandler::with_raw_events`.
+    Raw { topic: String, payload: Vec<u8> },
+}
+
+impl Event {
+    /// Interpret the attributes of a `DeviceAttributesChanged` event by their type. Unknown
+    /// attributes, and values that could not be interpreted, are kept as
+    /// `TypedChange::Raw`. Other events have no changes.
+    pub fn typed_changes(&self) -> Vec<TypedChange> {
+        match self {
+            Self::DeviceAttributesChanged { attributes, .. } => attributes
+                .iter()
+                .map(|(attribute, value)| TypedChange::from_raw(attribute, value))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
 }
 
 impl Client {
     /// Create a new Sengled client with a given username and password.
     pub fn new(username: &str, password: &str) -> Self {
-        Self {
-            http: reqwest::Client::new(),
-            username: String::from(username),
-            password: String::from(password),
-            preferred_qos: QoS::AtMostOnce,
-            skip_server_check: false,
-            state: None,
-            session: None,
-        }
+        Self::builder(username, password)
+            .build()
+            .expect("failed to build the client")
+    }
+
+    /// Create a client backed by a fake Sengled cloud instead of the network, for testing code
+    /// built on this crate. The client is already logged in, see `MockTransport`.
+    #[cfg(feature = "mock")]
+    pub fn new_mock() -> (Self, MockTransport) {
+        let (transport, backend) = MockTransport::new();
+
+        let mut client = Self::new("mock", "mock");
+        client.mock = Some(backend);
+        client.session = Some(String::from("mock"));
+
+        (client, transport)
     }
 
-    // Set the preferred MQTT quality of service. Default is 0, at-most-once.
+    /// Create a builder for a Sengled client with a given username and password.
+    pub fn builder(username: &str, password: &str) -> ClientBuilder {
+        ClientBuilder::new(username, password)
+    }
+
+    /// Set the preferred MQTT quality of service, used both when publishing attributes and
+    /// when subscribing to device events. Default is 0, at-most-once.
     pub fn with_preferred_qos(mut self, qos: QoS) -> Self {
         self.preferred_qos = qos;
         self
     }
 
+    /// Set the region of the account, which determines the cloud endpoints used. Default is
+    /// `Region::Us`.
+    pub fn with_region(mut self, region: Region) -> Self {
+        self.region = region;
+        self
+    }
+
     /// Skip the server check. Uses default MQTT server instead of one dynamically fetched.
     pub fn with_skip_server_check(mut self) -> Self {
         self.skip_server_check = true;
         self
     }
 
+    /// Use the given MQTT server instead of fetching one from the API, such as
+    /// `wss://eu-mqtt.cloud.sengled.com:443/mqtt` for accounts outside of the US. The URL is
+    /// validated by `start`, which returns `Error::Parse` if it is malformed and
+    /// `Error::InvalidServerUrl` if it has no host or a scheme other than `wss`.
+    pub fn with_server_url(mut self, url: impl Into<String>) -> Self {
+        self.server_url = Some(url.into());
+        self
+    }
+
+    /// Set the MQTT keep-alive interval. Default is 30 seconds. Intervals shorter than
+    /// 5 seconds are rejected by `start` with `Error::OutOfRange`.
+    pub fn with_keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    /// Add a random offset of up to `max` to the keep-alive interval of each connection, so
+    /// that many clients started together don't ping the broker in lockstep. The offset is
+    /// drawn from `0..max` and rounded down to whole seconds. Default is no jitter.
+    pub fn with_keep_alive_jitter(mut self, max: Duration) -> Self {
+        self.keep_alive_jitter = max;
+        self
+    }
+
+    /// Seed the random source used for keep-alive and reconnection jitter, making the offsets
+    /// reproducible, such as in tests. By default the source is seeded randomly.
+    pub fn with_jitter_seed(mut self, seed: u64) -> Self {
+        self.jitter_seed = Some(seed);
+        self
+    }
+
+    /// Coalesce rapid sets of the same attribute on the same device, such as from a slider,
+    /// so that only the latest value within `window` is published. The first set starts the
+    /// window and the latest value is always sent when it ends, so setting an attribute is
+    /// delayed by up to `window`. `close` sends any values still waiting.
+    ///
+    /// This applies to `set_device_attribute` and the methods built on it, but not to
+    /// `set_device_attribute_acked`, `set_device_attribute_tracked` or bulk sets.
+    pub fn with_debounce(mut self, window: Duration) -> Self {
+        self.debounce = Some(Debouncer::new(window));
+        self
+    }
+
+    /// Limit outbound publishes to at most `max_per_second`, including those of bulk methods
+    /// like `set_attributes_on_many`. Bursts of up to `max_per_second` are sent right away,
+    /// after which publishing waits for the limit to allow more instead of dropping messages.
+    /// Default is unlimited.
+    pub fn with_rate_limit(mut self, max_per_second: u32) -> Self {
+        self.rate_limit = Some(max_per_second);
+        self
+    }
+
+    /// Check attributes against the device type's `writable_attributes` before publishing,
+    /// returning `Error::UnsupportedAttribute` instead of sending a set the device would
+    /// silently ignore, such as one with a misspelled attribute.
+    ///
+    /// Only sets given a `Device` can be checked, since a plain MAC carries no type. Sets on
+    /// MACs and on devices of an unknown type are published unchecked. Off by default.
+    pub fn with_strict_attributes(mut self) -> Self {
+        self.strict_attributes = true;
+        self
+    }
+
+    /// Skip every MQTT publish, including attribute sets, bulk sets and `publish_raw`, so that
+    /// automations can be run against real devices without changing them. Skipped publishes
+    /// return `Ok` as if they had been sent and acknowledged, and are logged at the info
+    /// level when the `tracing` feature is enabled.
+    ///
+    /// Only publishes are skipped: logging in, fetching devices, subscribing and receiving
+    /// events all work normally.
+    pub fn with_dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+
+    /// Set how many requests, such as publishes, can be queued for the MQTT event loop before
+    /// publishing waits for room. Default is 10. A larger buffer absorbs bursts, such as
+    /// `set_attributes_on_many` across many devices, at the cost of memory and of more
+    /// messages being lost if the connection drops with them queued.
+    pub fn with_mqtt_capacity(mut self, capacity: usize) -> Self {
+        self.mqtt_capacity = capacity;
+        self
+    }
+
+    /// Set how long `start` and reconnection attempts wait for the MQTT server to accept the
+    /// connection before failing with `Error::ConnectionFailure`. Default is 15 seconds.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Use the given HTTP client for requests to the Sengled API, instead of one created by
+    /// the client. Its settings, such as timeouts, proxies and TLS roots, are used as-is.
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Have the broker publish a last-will message if the client disconnects uncleanly, so
+    /// other subscribers can tell the controller dropped. This only affects the MQTT session,
+    /// not requests to the HTTP API.
+    pub fn with_last_will(
+        mut self,
+        topic: String,
+        payload: Vec<u8>,
+        qos: QoS,
+        retain: bool,
+    ) -> Self {
+        self.last_will = Some(LastWill::new(topic, payload, qos, retain));
+        self
+    }
+
+    /// Set a timeout on HTTP requests made to the Sengled API. By default, there is no
+    /// timeout, and a hung endpoint will leave requests waiting forever.
+    ///
+    /// The timeout applies to each request as a whole, and separately to establishing the
+    /// connection. Timed out requests return `Error::Reqwest`. Replaces any client given to
+    /// `with_http_client`.
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// let client = sengled::Client::new("username", "password")
+    ///     .with_http_timeout(Duration::from_secs(10));
+    /// ```
+    pub fn with_http_timeout(mut self, timeout: Duration) -> Self {
+        self.http_timeout = Some(timeout);
+        self.http = build_http_client(self.http_timeout, self.http_proxy.clone())
+            .expect("failed to build the HTTP client");
+        self
+    }
+
+    /// Route HTTP requests made to the Sengled API through a proxy. Replaces any client given
+    /// to `with_http_client`.
+    ///
+    /// This does not apply to the MQTT connection, since a `reqwest::Proxy` cannot be handed
+    /// to the MQTT client. With the `proxy` feature enabled, use `with_mqtt_proxy` for that.
+    pub fn with_proxy(mut self, proxy: reqwest::Proxy) -> Self {
+        self.http_proxy = Some(proxy);
+        self.http = build_http_client(self.http_timeout, self.http_proxy.clone())
+            .expect("failed to build the HTTP client");
+        self
+    }
+
+    /// Route the MQTT websocket connection through a proxy.
+    #[cfg(feature = "proxy")]
+    pub fn with_mqtt_proxy(mut self, proxy: MqttProxy) -> Self {
+        self.mqtt_proxy = Some(proxy);
+        self
+    }
+
+    /// Retry HTTP requests to the Sengled API up to `attempts` more times when they fail to
+    /// connect, time out or get a 5xx response, waiting `backoff` before the first retry and
+    /// doubling the wait after each. Other responses, such as rejected credentials, are never
+    /// retried. By default, requests are not retried.
+    pub fn with_http_retries(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.http_retries = attempts;
+        self.http_backoff = backoff;
+        self
+    }
+
+    /// Send a request to the API, mapping 401 and 403 responses to `Error::SessionExpired`.
     async fn post<T: Serialize>(&self, url: &str, body: T) -> Result<Response, Error> {
+        let response = self.send_post(url, body).await?;
+
+        if matches!(
+            response.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        ) {
+            return Err(Error::SessionExpired);
+        }
+
+        Ok(response)
+    }
+
+    /// Send a request to the API, retrying transient failures, whatever the response status.
+    async fn send_post<T: Serialize>(&self, url: &str, body: T) -> Result<Response, Error> {
+        let body = serde_json::to_string(&body)?;
+        let mut backoff = self.http_backoff;
+        let mut attempt = 0;
+
+        let response = loop {
+            let result = self.send_once(url, &body).await;
+            let transient = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(error) => error.is_connect() || error.is_timeout(),
+            };
+
+            if !transient || attempt >= self.http_retries {
+                break result?;
+            }
+
+            #[cfg(feature = "tracing")]
+            tracing::debug!(attempt, ?backoff, "retrying HTTP request");
+
+            attempt += 1;
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        };
+
+        Ok(response)
+    }
+
+    /// Send a single request to the API, or to the mock cloud if there is one.
+    async fn send_once(&self, url: &str, body: &str) -> Result<Response, reqwest::Error> {
+        #[cfg(feature = "mock")]
+        if let Some(mock) = &self.mock {
+            return Ok(mock.transport().respond(url).into());
+        }
+
         let mut request = self
             .http
             .post(url)
             .header("Content-Type", "application/json")
-            .header("Host", "element.cloud.sengled.com:443")
             .header("Connection", "keep-alive");
 
+        if let Some(host) = self.region.host_header() {
+            request = request.header("Host", host);
+        }
+
         if let Some(session) = &self.session {
             request = request.header("Cookie", format!("JSESSIONID={}", session));
         }
 
-        Ok(request.body(serde_json::to_string(&body)?).send().await?)
+        request.body(body.to_owned()).send().await
     }
 
-    async fn post_with_session<T: Serialize>(
-        &self,
-        url: &str,
-        session: &str,
-        body: T,
-    ) -> Result<Response, Error> {
-        let request = self
-            .http
-            .post(url)
-            .header("Content-Type", "application/json")
-            .header("Host", "element.cloud.sengled.com:443")
-            .header("Connection", "keep-alive")
-            .header("Cookie", format!("JSESSIONID={}", session));
+    /// Whether `start` has completed and the client has not been closed since.
+    pub fn is_started(&self) -> bool {
+        self.state.is_some()
+    }
+
+    /// Whether the MQTT connection is currently up. This is updated as the `EventHandler`
+    /// observes the connection dropping and coming back, so it is only live while the handler
+    /// is being polled. Always `false` before `start`.
+    pub fn is_connected(&self) -> bool {
+        self.state
+            .as_ref()
+            .is_some_and(|state| state.mqtt_connected.load(Ordering::Relaxed))
+    }
+
+    /// Get the underlying MQTT client, once started, to publish or subscribe to topics this
+    /// crate does not model. This is an escape hatch: subscriptions made through it are not
+    /// tracked, so they are not restored after a reconnect, and unsubscribing from device
+    /// topics directly leaves `subscribed_devices` out of sync.
+    ///
+    /// Publish with `QoS::AtLeastOnce` or `QoS::ExactlyOnce` through `publish_raw` instead,
+    /// never through this client. Acknowledgements are matched to this crate's publishes in
+    /// the order they were sent, so an untracked publish that takes a packet id makes later
+    /// acknowledged sets, such as `set_device_attribute_acked`, resolve the wrong call or time
+    /// out. `QoS::AtMostOnce` publishes are never acknowledged and are safe.
+    pub fn mqtt_client(&self) -> Option<&MqttClient> {
+        self.state.as_ref().map(|state| &state.mqtt)
+    }
 
-        Ok(request.body(serde_json::to_string(&body)?).send().await?)
+    /// Get a snapshot of the client's publish, event, reconnect and error counters. Events
+    /// and reconnects are counted by the `EventHandler`, so they only advance while it is
+    /// being polled. All zero before `start`.
+    pub fn metrics(&self) -> Metrics {
+        self.state
+            .as_ref()
+            .map(|state| state.counters.snapshot())
+            .unwrap_or_default()
+    }
+
+    /// The server info fetched from the API while starting, which names the MQTT server.
+    /// `None` before `start`, or if the server was given with `with_server_url` or the
+    /// server check was skipped.
+    pub fn server_info(&self) -> Option<&ServerInfo> {
+        self.state.as_ref()?.server_info.as_ref()
+    }
+
+    /// The address of the MQTT server the client connected to, once started.
+    pub fn server_address(&self) -> Option<&str> {
+        self.state.as_ref().map(|state| state.server_url.as_str())
     }
 
     pub fn session(&self) -> Option<&str> {
         self.session.as_deref()
     }
 
+    /// Set the session to use for the API. On a started client, reconnects to the MQTT server
+    /// use it too.
     pub fn set_session(&mut self, value: impl Into<String>) {
-        self.session = Some(value.into());
+        self.store_session(value.into());
     }
 
-    pub async fn login(&mut self) -> Result<(), Error> {
-        const ROUTE: &str =
-            "https://ucenter.cloud.sengled.com/user/app/customer/v2/AuthenCross.json";
+    /// Replace the session, sharing it with the connection so that reconnects use it.
+    fn store_session(&mut self, session: String) {
+        if let Some(state) = &self.state {
+            *state.session.write().unwrap() = session.to_owned();
+        }
 
+        self.session = Some(session);
+    }
+
+    /// Log in with the username and password to get a session for the API. Fails with
+    /// `Error::LoggedIn` once the client has been started; to replace an expired session on a
+    /// running client without touching the MQTT connection, use `relogin` instead.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "sengled::login", skip_all, fields(username = %self.username))
+    )]
+    pub async fn login(&mut self) -> Result<(), Error> {
         if self.state.is_some() {
             return Err(Error::LoggedIn);
         }
 
+        self.session = Some(self.authenticate().await?);
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!("logged in");
+
+        Ok(())
+    }
+
+    /// Log in again to replace an expired session, even if the client has been started. Unlike
+    /// `login`, this never fails with `Error::LoggedIn`. The current MQTT connection is left
+    /// alone, but later reconnects use the new session, so long-running services should call
+    /// this whenever the API returns `Error::SessionExpired` to keep reconnects working.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "sengled::login", skip_all, fields(username = %self.username))
+    )]
+    pub async fn relogin(&mut self) -> Result<(), Error> {
+        let session = self.authenticate().await?;
+        self.store_session(session);
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!("refreshed session");
+
+        Ok(())
+    }
+
+    /// Log in again to replace an expired session, see `relogin`.
+    pub async fn refresh_session(&mut self) -> Result<(), Error> {
+        self.relogin().await
+    }
+
+    /// Log in with the username and password, returning the new session.
+    async fn authenticate(&self) -> Result<String, Error> {
+        const ROUTE: &str = "/user/app/customer/v2/AuthenCross.json";
+
         #[derive(Deserialize)]
         struct LoginResponse {
+            #[serde(default)]
+            ret: i64,
+
+            #[serde(default)]
+            msg: String,
+
             #[serde(rename = "jsessionId")]
-            session: String,
+            session: Option<String>,
         }
 
+        // rejected credentials may come with a 401, which is not an expired session here
         let data = self
-            .post(
-                ROUTE,
+            .send_post(
+                &self.region.ucenter_route(ROUTE),
                 json!({
                     "uuid": "xxxxxx",
                     "user": self.username,
@@ -155,185 +647,782 @@ impl Client {
             )
             .await?;
 
-        self.session = Some(data.json::<LoginResponse>().await?.session);
+        let status = data.status();
+        let response = match data.json::<LoginResponse>().await {
+            Ok(response) => response,
+            Err(_) if status.is_client_error() => {
+                return Err(Error::LoginFailed {
+                    code: status.as_u16() as i64,
+                    message: status.to_string(),
+                })
+            }
+            Err(error) => return Err(error.into()),
+        };
 
-        Ok(())
+        match response.session {
+            Some(session) if response.ret == 0 => Ok(session),
+            _ => Err(Error::LoginFailed {
+                code: response.ret,
+                message: response.msg,
+            }),
+        }
     }
 
-    /// Start the client given a jsessionId.
-    pub async fn start(&mut self) -> Result<EventHandler, Error> {
-        let (state, handler) = self.create_client_state().await?;
-        self.state = Some(state);
-        Ok(handler)
-    }
+    /// Get details about the account the session belongs to, such as to check that the
+    /// configured credentials resolved to the expected account. The user info endpoint
+    /// (`/user/app/customer/v2/getUserInfo.json` on the account API) is unconfirmed.
+    pub async fn account_info(&self) -> Result<AccountInfo, Error> {
+        const ROUTE: &str = "/user/app/customer/v2/getUserInfo.json";
 
-    async fn create_client_state(&mut self) -> Result<(ClientState, EventHandler), Error> {
-        assert!(
-            self.session.is_some(),
-            "session has not been set! please use `login` or `set_session`"
-        );
+        if self.session.is_none() {
+            return Err(Error::NoSession);
+        }
 
-        const ROUTE: &str = "https://life2.cloud.sengled.com/life2/server/getServerInfo.json";
-        const DEFAULT_SERVER_URL: &str = "wss://us-mqtt.cloud.sengled.com:443/mqtt";
+        let mut response = self
+            .post(&self.region.ucenter_route(ROUTE), json!({}))
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
 
-        let session = self.session.as_ref().unwrap();
+        let code = response
+            .get("ret")
+            .and_then(serde_json::Value::as_i64)
+            .unwrap_or(0);
+        if code != 0 {
+            return Err(Error::Api {
+                code,
+                message: response
+                    .get("msg")
+                    .and_then(serde_json::Value::as_str)
+                    .unwrap_or_default()
+                    .to_owned(),
+            });
+        }
 
-        let url = if self.skip_server_check {
-            Url::parse(DEFAULT_SERVER_URL)?
-        } else {
-            #[derive(Deserialize)]
-            struct ServerInfoResponse {
-                #[serde(rename = "inceptionAddr")]
-                addr: String,
-            }
+        // the details are either nested or sent alongside `ret`
+        let info = ["customerInfo", "userInfo", "data"]
+            .iter()
+            .find(|key| {
+                response
+                    .get(**key)
+                    .is_some_and(serde_json::Value::is_object)
+            })
+            .and_then(|key| response.get_mut(*key))
+            .map(serde_json::Value::take)
+            .unwrap_or(response);
+
+        Ok(serde_json::from_value(info)?)
+    }
 
-            let response = self
-                .post_with_session(ROUTE, session, json!({}))
-                .await?
-                .json::<ServerInfoResponse>()
-                .await?;
+    /// Check whether the current session is still accepted by the API, such as one loaded
+    /// from disk. Returns `Ok(false)` if the API rejects it.
+    pub async fn validate_session(&self) -> Result<bool, Error> {
+        const ROUTE: &str = "/life2/device/list.json";
 
-            println!("{}", response.addr);
+        if self.session.is_none() {
+            return Err(Error::NoSession);
+        }
 
-            Url::parse(&response.addr)?
+        let response = match self.post(&self.region.life2_route(ROUTE), json!({})).await {
+            Ok(response) => response,
+            Err(Error::SessionExpired) => return Ok(false),
+            Err(error) => return Err(error),
         };
 
-        let mut mqtt_options = MqttOptions::new(
-            format!("{}@lifeApp", session.to_owned()),
-            format!("wss://{}{}", url.host_str().unwrap(), url.path()),
-            url.port().unwrap_or(443),
-        );
+        // a rejected session responds with an error code instead of the device list
+        let body = response.json::<serde_json::Value>().await?;
+        if body.get("deviceList").is_some() {
+            return Ok(true);
+        }
 
-        let modifier_session = session.to_owned();
-        mqtt_options
-            .set_transport(Transport::wss_with_default_config())
-            .set_keep_alive(Duration::from_secs(30))
-            .set_request_modifier(move |mut request| {
-                let modifier_session = modifier_session.to_owned();
-
-                async move {
-                    let headers = request.headers_mut();
-                    headers.insert(
-                        "Cookie",
-                        format!("JSESSIONID={}", modifier_session).parse().unwrap(),
-                    );
-                    headers.insert("X-Requested-With", "com.sengled.life2".parse().unwrap());
-
-                    request
-                }
-            });
+        match api_error(&body) {
+            Error::SessionExpired => Ok(false),
+            error => Err(error),
+        }
+    }
+
+    /// Start the client given a jsessionId.
+    pub async fn start(&mut self) -> Result<EventHandler, Error> {
+        let (state, handler) = self.create_client_state().await?;
+        self.state = Some(state);
+        Ok(handler)
+    }
 
-        let (client, mut events) = MqttClient::new(mqtt_options, 10);
+    /// Log in and start the client in one step, see `login` and `start`. The returned handler
+    /// works the same as one from `start`, so it must still be polled or spawned with
+    /// `spawn_listener`.
+    pub async fn login_and_start(&mut self) -> Result<EventHandler, Error> {
+        self.login().await?;
+        self.start().await
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "sengled::start", skip_all)
+    )]
+    async fn create_client_state(&mut self) -> Result<(ClientState, EventHandler), Error> {
+        validate_keep_alive(self.keep_alive)?;
+
+        let config = ConnectionConfig {
+            http: self.http.clone(),
+            session: Arc::new(RwLock::new(self.session.clone().ok_or(Error::NoSession)?)),
+            region: self.region,
+            skip_server_check: self.skip_server_check,
+            server_url: self
+                .server_url
+                .as_deref()
+                .map(MqttServer::parse)
+                .transpose()?,
+            keep_alive: self.keep_alive,
+            keep_alive_jitter: self.keep_alive_jitter,
+            jitter: Jitter::new(self.jitter_seed),
+            connect_timeout: self.connect_timeout,
+            mqtt_capacity: self.mqtt_capacity,
+            rate_limit: self.rate_limit,
+            dry_run: self.dry_run,
+            qos: self.preferred_qos,
+            last_will: self.last_will.clone(),
+            #[cfg(feature = "proxy")]
+            mqtt_proxy: self.mqtt_proxy.clone(),
+        };
 
-        match events.poll().await {
-            Ok(MqttEvent::Incoming(Incoming::ConnAck(ConnAck {
-                code: ConnectReturnCode::Success,
-                ..
-            }))) => (),
-            _ => return Err(Error::ConnectionFailure),
+        #[cfg(feature = "mock")]
+        if let Some(mock) = &mut self.mock {
+            return mock.start(config);
         }
 
+        let (server, server_info) = config.server().await?;
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(server = %server.url, "connecting to the MQTT server");
+        let (client, mut events) =
+            MqttClient::new(config.mqtt_options(&server), config.mqtt_capacity);
+        config.wait_for_connack(&mut events).await?;
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!("connected to the MQTT server");
+
+        let subscriptions = Subscriptions::default();
+        let counters = Arc::new(Counters::default());
+        let acks = Arc::new(AckTracker::new(
+            config.rate_limit,
+            config.dry_run,
+            Arc::clone(&counters),
+        ));
+        let mqtt_connected = Arc::new(AtomicBool::new(true));
+
         Ok((
             ClientState {
-                mqtt: client,
+                mqtt: client.clone(),
                 listener_handle: None,
+                subscriptions: Arc::clone(&subscriptions),
+                server_url: server.url,
+                server_info,
+                acks: Arc::clone(&acks),
+                mqtt_connected: Arc::clone(&mqtt_connected),
+                counters: Arc::clone(&counters),
+                session: Arc::clone(&config.session),
+            },
+            EventHandler {
+                events,
+                mqtt: client,
+                config,
+                subscriptions,
+                acks,
+                mqtt_connected,
+                counters,
+                reconnect_policy: None,
+                raw_events: false,
+                connected: false,
+                disconnected: false,
+                closed: false,
+                #[cfg(feature = "mock")]
+                mock_events: None,
             },
-            EventHandler { events },
         ))
     }
 
+    fn state(&self) -> Result<&ClientState, Error> {
+        self.state.as_ref().ok_or(Error::NotStarted)
+    }
+
     /// Get a list of WIFI devices registered to the account.
     pub async fn wifi_devices(&self) -> Result<Vec<Device>, Error> {
-        assert!(self.state.is_some(), "not logged in");
+        Ok(serde_json::from_value(self.wifi_devices_raw().await?)?)
+    }
 
-        const ROUTE: &str = "https://life2.cloud.sengled.com/life2/device/list.json";
+    /// Get a list of WIFI devices registered to the account, parsing each device on its own.
+    /// Returns the devices that parsed, and the raw JSON of the ones that did not.
+    pub async fn wifi_devices_lenient(
+        &self,
+    ) -> Result<(Vec<Device>, Vec<serde_json::Value>), Error> {
+        let raw = match self.wifi_devices_raw().await? {
+            serde_json::Value::Array(raw) => raw,
+            other => return Ok((vec![], vec![other])),
+        };
 
-        #[derive(Deserialize)]
-        struct DevicesResponse {
-            #[serde(rename = "deviceList")]
-            devices: Vec<Device>,
+        let mut devices = vec![];
+        let mut failed = vec![];
+        for value in raw {
+            match Device::deserialize(&value) {
+                Ok(device) => devices.push(device),
+                Err(_) => failed.push(value),
+            }
         }
 
-        Ok(self
-            .post(ROUTE, json!({}))
-            .await?
-            .json::<DevicesResponse>()
-            .await?
-            .devices)
+        Ok((devices, failed))
     }
 
-    /// Subscribe to WIFI device events after fetching them. Returns a list of devices.
-    pub async fn get_wifi_devices_and_subscribe(&self) -> Result<Vec<Device>, Error> {
-        assert!(self.state.is_some(), "not logged in");
+    /// Get the list of WIFI devices registered to the account as untyped JSON, exactly as the
+    /// API returned it. Useful for inspecting fields that `Device` does not know about.
+    ///
+    /// ```no_run
+    /// # async fn example(client: &sengled::Client) -> Result<(), sengled::Error> {
+    /// let raw = client.wifi_devices_raw().await?;
+    /// let devices = client.wifi_devices().await?;
+    ///
+    /// for (raw, device) in raw.as_array().unwrap().iter().zip(&devices) {
+    ///     println!("raw:   {}", raw);
+    ///     println!("typed: {}", serde_json::to_string(device)?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn wifi_devices_raw(&self) -> Result<serde_json::Value, Error> {
+        self.state()?;
+
+        const ROUTE: &str = "/life2/device/list.json";
+
+        let mut body = self
+            .post(&self.region.life2_route(ROUTE), json!({}))
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
 
-        let devices = self.wifi_devices().await?;
-        self.subscribe_devices(&devices).await?;
-        Ok(devices)
+        // a rejected session responds with an error code instead of the device list
+        match body.get_mut("deviceList") {
+            Some(devices) => Ok(devices.take()),
+            None => Err(api_error(&body)),
+        }
     }
 
-    /// Subscribe the event listener to a single device.
-    pub async fn subscribe_device(&self, device: impl AsDeviceMac) -> Result<(), Error> {
-        assert!(self.state.is_some(), "not logged in");
+    /// Get a list of Zigbee devices connected to the account's hubs, from the hub device
+    /// endpoint (`/zigbee/device/getDeviceDetails.json` on `Region::element_base`). These
+    /// devices have the `zigbee` category.
+    pub async fn zigbee_devices(&self) -> Result<Vec<Device>, Error> {
+        self.state()?;
 
-        self.state
-            .as_ref()
-            .unwrap()
-            .mqtt
-            .subscribe(
-                format!("wifielement/{}/status", device.as_device_mac()),
-                QoS::AtMostOnce,
-            )
+        const ROUTE: &str = "/zigbee/device/getDeviceDetails.json";
+
+        #[derive(Deserialize)]
+        struct HubsResponse {
+            #[serde(default, rename = "deviceInfos")]
+            hubs: Vec<Hub>,
+        }
+
+        #[derive(Deserialize)]
+        struct Hub {
+            #[serde(default, rename = "lampInfos")]
+            devices: Vec<serde_json::Value>,
+        }
+
+        let response = self
+            .post(&self.region.element_route(ROUTE), json!({}))
+            .await?
+            .json::<HubsResponse>()
             .await?;
 
-        Ok(())
+        response
+            .hubs
+            .into_iter()
+            .flat_map(|hub| hub.devices)
+            .map(|mut device| {
+                // hub devices leave out the category, and keep the type code with the attributes
+                if let Some(device) = device.as_object_mut() {
+                    let type_code = device
+                        .get("attributes")
+                        .and_then(|attributes| attributes.get("typeCode"))
+                        .cloned()
+                        .unwrap_or_else(|| json!(""));
+
+                    device.entry("category").or_insert_with(|| json!("zigbee"));
+                    device.entry("typeCode").or_insert(type_code);
+                }
+
+                Ok(serde_json::from_value(device)?)
+            })
+            .collect()
     }
 
-    /// Subscribe the event listener to many devices.
-    pub async fn subscribe_devices(&self, devices: &[impl AsDeviceMac]) -> Result<(), Error> {
-        assert!(self.state.is_some(), "not logged in");
+    /// Get the rooms the account's devices are organized into.
+    pub async fn rooms(&self) -> Result<Vec<Room>, Error> {
+        self.state()?;
 
-        self.state
-            .as_ref()
-            .unwrap()
-            .mqtt
-            .subscribe_many(devices.iter().map(|device| SubscribeFilter {
-                path: format!("wifielement/{}/status", device.as_device_mac()),
-                qos: QoS::AtMostOnce,
-            }))
-            .await?;
+        const ROUTE: &str = "/life2/room/list.json";
+
+        #[derive(Deserialize)]
+        struct RoomsResponse {
+            #[serde(default, rename = "roomList")]
+            rooms: Vec<Room>,
+        }
 
+        Ok(self
+            .post(&self.region.life2_route(ROUTE), json!({}))
+            .await?
+            .json::<RoomsResponse>()
+            .await?
+            .rooms)
+    }
+
+    /// Get a single WIFI device registered to the account, with its current attributes.
+    /// Returns `Error::DeviceNotFound` if no device has the given MAC.
+    pub async fn get_device(&self, device: impl AsDeviceMac) -> Result<Device, Error> {
+        let mac = device.normalized_mac();
+
+        self.wifi_devices()
+            .await?
+            .into_iter()
+            .find(|device| device.normalized_mac() == mac)
+            .ok_or_else(|| Error::DeviceNotFound(device.as_device_mac().to_owned()))
+    }
+
+    /// Re-fetch a device from the device list and overwrite its attributes, to reconcile
+    /// state that may have changed while no events were being received. Returns
+    /// `Error::DeviceNotFound` if the device is no longer on the account.
+    pub async fn refresh_device_state(&self, device: &mut Device) -> Result<(), Error> {
+        device.attributes = self.get_device(&*device).await?.attributes;
         Ok(())
     }
 
-    /// Set an attribute on a device.
-    pub async fn set_device_attribute(
+    /// Rename a device. The new name shows up in the device list, under the `name` attribute,
+    /// the next time it is fetched; local `Device` values are not updated.
+    pub async fn rename_device(
         &self,
         device: impl AsDeviceMac,
-        attribute: &str,
-        value: &str,
+        new_name: &str,
     ) -> Result<(), Error> {
-        assert!(self.state.is_some(), "not logged in");
+        self.state()?;
 
-        let body = json!({
-            "dn": device.as_device_mac(),
-            "type": attribute,
-            "value": value,
-            "time": chrono::Utc::now().timestamp_millis(),
-        });
+        const ROUTE: &str = "/life2/device/updateDeviceName.json";
 
-        self.state
-            .as_ref()
+        #[derive(Deserialize)]
+        struct RenameResponse {
+            #[serde(default)]
+            ret: i64,
+
+            #[serde(default)]
+            msg: String,
+        }
+
+        let response = self
+            .post(
+                &self.region.life2_route(ROUTE),
+                json!({
+                    "deviceUuid": device.as_device_mac(),
+                    "deviceName": new_name,
+                }),
+            )
+            .await?
+            .json::<RenameResponse>()
+            .await?;
+
+        if response.ret != 0 {
+            return Err(Error::Api {
+                code: response.ret,
+                message: response.msg,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Subscribe to WIFI device events after fetching them. Returns a list of devices.
+    pub async fn get_wifi_devices_and_subscribe(&self) -> Result<Vec<Device>, Error> {
+        let devices = self.wifi_devices().await?;
+        self.subscribe_devices(&devices).await?;
+        Ok(devices)
+    }
+
+    /// Subscribe the event listener to a single device.
+    pub async fn subscribe_device(&self, device: impl AsDeviceMac) -> Result<(), Error> {
+        let state = self.state()?;
+
+        let topic = status_topic(device.device_source(), device.as_device_mac());
+        state.mqtt.subscribe(&topic, self.preferred_qos).await?;
+
+        state
+            .subscriptions
+            .lock()
             .unwrap()
+            .insert(device.normalized_mac(), topic);
+
+        Ok(())
+    }
+
+    /// Subscribe the event listener to many devices.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "sengled::subscribe", skip_all, fields(count = devices.len()))
+    )]
+    pub async fn subscribe_devices(&self, devices: &[impl AsDeviceMac]) -> Result<(), Error> {
+        let state = self.state()?;
+
+        #[cfg(feature = "tracing")]
+        for device in devices {
+            tracing::trace!(device = device.as_device_mac(), "subscribing");
+        }
+
+        state
             .mqtt
+            .subscribe_many(devices.iter().map(|device| SubscribeFilter {
+                path: status_topic(device.device_source(), device.as_device_mac()),
+                qos: self.preferred_qos,
+            }))
+            .await?;
+
+        state
+            .subscriptions
+            .lock()
+            .unwrap()
+            .extend(devices.iter().map(|device| {
+                (
+                    device.normalized_mac(),
+                    status_topic(device.device_source(), device.as_device_mac()),
+                )
+            }));
+
+        Ok(())
+    }
+
+    /// Subscribe the event listener to every device on the account with a wildcard topic, so
+    /// devices added later are covered without fetching the device list.
+    ///
+    /// Wildcard subscriptions are not documented by Sengled, and the broker may reject them,
+    /// in which case no events arrive and `subscribe_devices` should be used instead. The
+    /// wildcard is restored after a reconnect like any other subscription, but it is not
+    /// included in `subscribed_devices`.
+    pub async fn subscribe_all(&self) -> Result<(), Error> {
+        let state = self.state()?;
+
+        let topic = status_topic(DeviceSource::Wifi, WILDCARD);
+        state.mqtt.subscribe(&topic, self.preferred_qos).await?;
+
+        state
+            .subscriptions
+            .lock()
+            .unwrap()
+            .insert(String::from(WILDCARD), topic);
+
+        Ok(())
+    }
+
+    /// Get the MACs of every device the event listener is subscribed to, such as to persist
+    /// them and `resubscribe` after a restart. MACs are given as they were subscribed with,
+    /// since that is the form the device uses in its topics.
+    pub fn subscribed_devices(&self) -> Vec<String> {
+        let Some(state) = &self.state else {
+            return vec![];
+        };
+
+        let mut macs = state
+            .subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(mac, _)| *mac != WILDCARD)
+            .filter_map(|(_, topic)| topic.split('/').nth(1).map(String::from))
+            .collect::<Vec<_>>();
+        macs.sort();
+        macs
+    }
+
+    /// Subscribe the event listener to devices previously returned by `subscribed_devices`.
+    /// Plain MACs are subscribed to as WIFI devices, so Zigbee devices should be subscribed
+    /// to again with their `Device` instead.
+    pub async fn resubscribe(&self, macs: &[String]) -> Result<(), Error> {
+        self.subscribe_devices(macs).await
+    }
+
+    /// Unsubscribe the event listener from a single device. The device may be given in any
+    /// form of its MAC, the topic it was subscribed with is unsubscribed from.
+    pub async fn unsubscribe_device(&self, device: impl AsDeviceMac) -> Result<(), Error> {
+        let state = self.state()?;
+
+        let subscribed = state
+            .subscriptions
+            .lock()
+            .unwrap()
+            .get(&device.normalized_mac())
+            .cloned();
+        let topic = subscribed
+            .unwrap_or_else(|| status_topic(device.device_source(), device.as_device_mac()));
+
+        state.mqtt.unsubscribe(topic).await?;
+
+        state
+            .subscriptions
+            .lock()
+            .unwrap()
+            .remove(&device.normalized_mac());
+
+        Ok(())
+    }
+
+    /// Unsubscribe the event listener from many devices.
+    pub async fn unsubscribe_devices(&self, devices: &[impl AsDeviceMac]) -> Result<(), Error> {
+        for device in devices {
+            self.unsubscribe_device(device.as_device_mac()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that an attribute is writable on the device, if strict attributes are enabled
+    /// and the device's type is known.
+    fn check_attribute(&self, device: &impl AsDeviceMac, attribute: &str) -> Result<(), Error> {
+        let Some(type_code) = device.device_type_code().filter(|_| self.strict_attributes) else {
+            return Ok(());
+        };
+
+        let device_type = DeviceType::from_type_code(type_code);
+        if matches!(device_type, DeviceType::Unknown(_))
+            || device_type.writable_attributes().contains(&attribute)
+        {
+            return Ok(());
+        }
+
+        Err(Error::UnsupportedAttribute {
+            attribute: String::from(attribute),
+            type_code: String::from(type_code),
+        })
+    }
+
+    /// Publish an update payload to a device.
+    async fn publish_update(
+        &self,
+        mac: &str,
+        payload: String,
+        qos: QoS,
+        sent: Option<oneshot::Sender<Result<u16, Error>>>,
+        ack: Option<oneshot::Sender<Result<(), Error>>>,
+    ) -> Result<(), Error> {
+        let state = self.state()?;
+
+        state
+            .acks
             .publish(
-                format!("wifielement/{}/update", device.as_device_mac()),
-                self.preferred_qos,
+                &state.mqtt,
+                update_topic(mac),
+                qos,
                 false,
-                serde_json::to_string(&body)?,
+                payload.into_bytes(),
+                Queued { sent, ack },
             )
-            .await?;
+            .await
+    }
 
-        Ok(())
+    /// Set an attribute on a device.
+    pub async fn set_device_attribute(
+        &self,
+        device: impl AsDeviceMac,
+        attribute: &str,
+        value: &str,
+    ) -> Result<(), Error> {
+        self.set_device_attribute_with_qos(device, attribute, value, self.preferred_qos)
+            .await
+    }
+
+    /// Set an attribute on a device, publishing at the given QoS instead of the one set with
+    /// `with_preferred_qos`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "sengled::publish",
+            skip_all,
+            fields(device = device.as_device_mac(), attribute = %attribute)
+        )
+    )]
+    pub async fn set_device_attribute_with_qos(
+        &self,
+        device: impl AsDeviceMac,
+        attribute: &str,
+        value: &str,
+        qos: QoS,
+    ) -> Result<(), Error> {
+        self.check_attribute(&device, attribute)?;
+
+        let body = json!({
+            "dn": device.as_device_mac(),
+            "type": attribute,
+            "value": value,
+            "time": now_millis(),
+        });
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(value, "publishing attribute");
+
+        if let Some(debounce) = &self.debounce {
+            let state = self.state()?;
+            debounce.publish(
+                &state.mqtt,
+                &state.acks,
+                device.as_device_mac(),
+                String::from(attribute),
+                serde_json::to_string(&body)?,
+                qos,
+            );
+            return Ok(());
+        }
+
+        self.publish_update(
+            device.as_device_mac(),
+            serde_json::to_string(&body)?,
+            qos,
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Set an attribute on a device at the given QoS, returning the MQTT packet id the publish
+    /// was sent with, such as to correlate it with the broker's ack. Publishes at
+    /// `QoS::AtMostOnce` have no packet id, so `None` is returned as soon as it is queued.
+    ///
+    /// Packet ids are assigned when the event loop sends the publish, so the `EventHandler`
+    /// must still be polled, either manually or with `spawn_listener`, while waiting.
+    pub async fn set_device_attribute_tracked(
+        &self,
+        device: impl AsDeviceMac,
+        attribute: &str,
+        value: &str,
+        qos: QoS,
+    ) -> Result<Option<u16>, Error> {
+        self.check_attribute(&device, attribute)?;
+
+        let body = json!({
+            "dn": device.as_device_mac(),
+            "type": attribute,
+            "value": value,
+            "time": now_millis(),
+        });
+
+        let (sent, packet_id) = oneshot::channel();
+        self.publish_update(
+            device.as_device_mac(),
+            serde_json::to_string(&body)?,
+            qos,
+            Some(sent),
+            None,
+        )
+        .await?;
+
+        // nothing was sent in a dry run, so there is no packet id
+        if qos == QoS::AtMostOnce || self.dry_run {
+            return Ok(None);
+        }
+
+        match packet_id.await {
+            Ok(packet_id) => packet_id.map(Some),
+            Err(_) => Err(Error::Disconnected),
+        }
+    }
+
+    /// Set an attribute on a device only if it differs from the device's current value, to
+    /// skip redundant commands. The client does not track device state, so the comparison is
+    /// made against the attributes on `device`, which should be kept up to date with events.
+    ///
+    /// Returns whether a command was published.
+    pub async fn set_device_attribute_if_changed(
+        &self,
+        device: &Device,
+        attribute: &str,
+        value: &str,
+    ) -> Result<bool, Error> {
+        if device.get_attribute(attribute) == Some(value) {
+            return Ok(false);
+        }
+
+        self.set_device_attribute(device, attribute, value).await?;
+        Ok(true)
+    }
+
+    /// Set an attribute on a device, waiting until the broker acknowledges it or `timeout`
+    /// elapses, in which case `Error::AckTimeout` is returned.
+    ///
+    /// Acknowledgements are only sent for QoS 1 and 2, see `with_preferred_qos`. At QoS 0, this
+    /// returns as soon as the message is queued, like `set_device_attribute`.
+    ///
+    /// Acks are observed by the event loop, so the `EventHandler` must still be polled, either
+    /// manually or with `spawn_listener`, while waiting. If the connection drops first,
+    /// `Error::ConnectionFailure` is returned once the handler starts reconnecting, since the
+    /// broker may or may not have received the message.
+    pub async fn set_device_attribute_acked(
+        &self,
+        device: impl AsDeviceMac,
+        attribute: &str,
+        value: &str,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        self.check_attribute(&device, attribute)?;
+
+        let body = json!({
+            "dn": device.as_device_mac(),
+            "type": attribute,
+            "value": value,
+            "time": now_millis(),
+        });
+
+        let (ack, acked) = oneshot::channel();
+        self.publish_update(
+            device.as_device_mac(),
+            serde_json::to_string(&body)?,
+            self.preferred_qos,
+            None,
+            Some(ack),
+        )
+        .await?;
+
+        if self.preferred_qos == QoS::AtMostOnce {
+            return Ok(());
+        }
+
+        match tokio::time::timeout(timeout, acked).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(Error::Disconnected),
+            Err(_) => Err(Error::AckTimeout),
+        }
+    }
+
+    /// Publish a raw message to any topic, for experimenting with topics this crate does not
+    /// model, such as group control. This is an advanced API: nothing about the topic or
+    /// payload is checked, and the broker may disconnect the client for messages it rejects.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "sengled::publish", skip_all, fields(topic = %topic))
+    )]
+    pub async fn publish_raw(
+        &self,
+        topic: &str,
+        payload: impl Into<Vec<u8>>,
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), Error> {
+        let state = self.state()?;
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!("publishing raw message");
+
+        state
+            .acks
+            .publish(
+                &state.mqtt,
+                String::from(topic),
+                qos,
+                retain,
+                payload.into(),
+                Queued::default(),
+            )
+            .await
     }
 
     /// Set attributes on a device.
@@ -342,104 +1431,1556 @@ impl Client {
         device: impl AsDeviceMac,
         attributes: &[(impl AsRef<str>, impl AsRef<str>)],
     ) -> Result<(), Error> {
-        assert!(self.state.is_some(), "not logged in");
+        let attributes = attributes
+            .iter()
+            .map(|(key, value)| (key.as_ref(), value.as_ref(), None))
+            .collect::<Vec<_>>();
+
+        self.set_device_attributes_at(device, &attributes).await
+    }
+
+    /// Set attributes on a device, each with the time it was set in milliseconds since the
+    /// epoch, such as when replaying recorded state. Attributes without a time are stamped
+    /// with the current time.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "sengled::publish",
+            skip_all,
+            fields(device = device.as_device_mac())
+        )
+    )]
+    pub async fn set_device_attributes_at(
+        &self,
+        device: impl AsDeviceMac,
+        attributes: &[(impl AsRef<str>, impl AsRef<str>, Option<i64>)],
+    ) -> Result<(), Error> {
+        for (key, _, _) in attributes.iter() {
+            self.check_attribute(&device, key.as_ref())?;
+        }
+
+        let now = now_millis();
 
         let mut body = vec![];
-        for (key, value) in attributes.iter() {
+        for (key, value, time) in attributes.iter() {
             body.push(json!({
                 "dn": device.as_device_mac(),
                 "type": key.as_ref(),
                 "value": value.as_ref(),
-                "time": chrono::Utc::now().timestamp_millis(),
+                "time": time.unwrap_or(now),
             }));
         }
 
-        self.state
-            .as_ref()
-            .unwrap()
-            .mqtt
-            .publish(
-                format!("wifielement/{}/update", device.as_device_mac()),
-                self.preferred_qos,
-                false,
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            attributes = ?attributes.iter().map(|(key, _, _)| key.as_ref()).collect::<Vec<_>>(),
+            "publishing attributes"
+        );
+
+        self.publish_update(
+            device.as_device_mac(),
+            serde_json::to_string(&body)?,
+            self.preferred_qos,
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Set the same attributes on many devices, publishing to each of them concurrently.
+    /// Returns the first error encountered.
+    pub async fn set_attributes_on_many(
+        &self,
+        devices: &[impl AsDeviceMac],
+        attributes: &[(impl AsRef<str>, impl AsRef<str>)],
+    ) -> Result<(), Error> {
+        for device in devices {
+            for (key, _) in attributes.iter() {
+                self.check_attribute(device, key.as_ref())?;
+            }
+        }
+
+        let time = now_millis();
+        let macs = devices
+            .iter()
+            .map(AsDeviceMac::as_device_mac)
+            .collect::<Vec<_>>();
+
+        #[derive(Serialize)]
+        struct Update<'a> {
+            dn: &'a str,
+            #[serde(rename = "type")]
+            name: &'a str,
+            value: &'a str,
+            time: i64,
+        }
+
+        let mut body = attributes
+            .iter()
+            .map(|(key, value)| Update {
+                dn: "",
+                name: key.as_ref(),
+                value: value.as_ref(),
+                time,
+            })
+            .collect::<Vec<_>>();
+
+        let mut publishes = Vec::with_capacity(macs.len());
+        for &mac in &macs {
+            for update in body.iter_mut() {
+                update.dn = mac;
+            }
+
+            publishes.push(self.publish_update(
+                mac,
                 serde_json::to_string(&body)?,
-            )
-            .await?;
+                self.preferred_qos,
+                None,
+                None,
+            ));
+        }
+
+        futures::future::try_join_all(publishes).await?;
 
         Ok(())
     }
 
-    /// Close the client, sending any remaining MQTT messages.
-    pub async fn close(mut self) -> Result<(), Error> {
-        if let Some(ClientState {
-            listener_handle,
-            mqtt,
-            ..
-        }) = self.state.take()
-        {
-            mqtt.disconnect().await?;
-            if let Some(listener_handle) = listener_handle {
+    /// Set a well-known attribute on a device.
+    pub async fn set_attribute(
+        &self,
+        device: impl AsDeviceMac,
+        attribute: Attribute,
+        value: &str,
+    ) -> Result<(), Error> {
+        self.set_device_attribute(device, attribute.as_wire_name(), value)
+            .await
+    }
+
+    /// Turn a device on by setting its `switch` attribute to `1`.
+    pub async fn turn_on(&self, device: impl AsDeviceMac) -> Result<(), Error> {
+        self.set_attribute(device, Attribute::Switch, "1").await
+    }
+
+    /// Turn a device off by setting its `switch` attribute to `0`.
+    pub async fn turn_off(&self, device: impl AsDeviceMac) -> Result<(), Error> {
+        self.set_attribute(device, Attribute::Switch, "0").await
+    }
+
+    /// Toggle a device's switch, given whether it is currently on. The client does not keep
+    /// track of device state, so the current value must be supplied. Returns the new state.
+    pub async fn toggle_device(
+        &self,
+        device: impl AsDeviceMac,
+        current: bool,
+    ) -> Result<bool, Error> {
+        let new_state = !current;
+        self.set_attribute(device, Attribute::Switch, if new_state { "1" } else { "0" })
+            .await?;
+        Ok(new_state)
+    }
+
+    /// Set the brightness of a device. The full `u8` range is accepted, where `0` is the
+    /// dimmest and `255` is the brightest; there is no value that can fall outside of it.
+    pub async fn set_brightness(&self, device: impl AsDeviceMac, value: u8) -> Result<(), Error> {
+        self.set_attribute(device, Attribute::Brightness, &value.to_string())
+            .await
+    }
+
+    /// Set the color temperature of a device in Kelvin. The value must be within
+    /// `2000..=7000`, otherwise `Error::OutOfRange` is returned.
+    pub async fn set_color_temperature_kelvin(
+        &self,
+        device: impl AsDeviceMac,
+        kelvin: u16,
+    ) -> Result<(), Error> {
+        let percentage = kelvin_to_color_temperature(kelvin)?;
+        self.set_attribute(device, Attribute::ColorTemperature, &percentage.to_string())
+            .await
+    }
+
+    /// Set the color mode of a device, see `ColorMode` for the assumed mapping.
+    pub async fn set_color_mode(
+        &self,
+        device: impl AsDeviceMac,
+        mode: ColorMode,
+    ) -> Result<(), Error> {
+        self.set_attribute(
+            device,
+            Attribute::ColorMode,
+            &mode.as_attribute_value().to_string(),
+        )
+        .await
+    }
+
+    /// Set the RGB color of a device.
+    pub async fn set_color(&self, device: impl AsDeviceMac, color: Color) -> Result<(), Error> {
+        self.set_attribute(device, Attribute::Color, &color.to_attribute_value())
+            .await
+    }
+
+    /// Close the client, sending any remaining MQTT messages. Waits up to 10 seconds for the
+    /// listener to finish, see `close_with_timeout`.
+    pub async fn close(self) -> Result<(), Error> {
+        self.close_with_timeout(DEFAULT_CLOSE_TIMEOUT).await
+    }
+
+    /// Close the client, sending any remaining MQTT messages. If flushing debounced sets,
+    /// queueing the disconnect and waiting for the listener together take longer than
+    /// `timeout`, the listener is aborted and `Error::ShutdownTimeout` is returned.
+    pub async fn close_with_timeout(mut self, timeout: Duration) -> Result<(), Error> {
+        let Some(mut state) = self.state.take() else {
+            return Ok(());
+        };
+
+        let mut listener_handle = state.listener_handle.take();
+        let shutdown = async {
+            if let Some(debounce) = &self.debounce {
+                debounce.flush(&state.mqtt, &state.acks).await?;
+            }
+
+            state.mqtt.disconnect().await?;
+            if let Some(listener_handle) = &mut listener_handle {
                 let _ = listener_handle.await;
             }
-        }
 
-        Ok(())
+            Ok(())
+        };
+
+        match tokio::time::timeout(timeout, shutdown).await {
+            Ok(result) => result,
+            Err(_) => {
+                if let Some(listener_handle) = listener_handle {
+                    listener_handle.abort();
+                }
+                Err(Error::ShutdownTimeout)
+            }
+        }
     }
 }
 
 #[must_use = "either start the basic listener with `spawn_listener` or manually poll events for the API to function"]
 pub struct EventHandler {
     events: rumqttc::EventLoop,
+    mqtt: MqttClient,
+    config: ConnectionConfig,
+    subscriptions: Subscriptions,
+    acks: Arc<AckTracker>,
+    mqtt_connected: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+    reconnect_policy: Option<ReconnectPolicy>,
+    raw_events: bool,
+    connected: bool,
+    disconnected: bool,
+    closed: bool,
+    #[cfg(feature = "mock")]
+    mock_events: Option<tokio::sync::mpsc::UnboundedReceiver<Event>>,
 }
 
 impl EventHandler {
+    /// Automatically reconnect when the MQTT connection drops, instead of returning
+    /// `Error::Disconnected` from `poll`. `Event::Disconnected` is emitted when the connection
+    /// drops, then previously subscribed devices are resubscribed and `Event::Reconnected` is
+    /// emitted once the connection is back.
+    pub fn with_auto_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = Some(policy);
+        self
+    }
+
+    /// Emit `Event::Raw` for publishes on topics that are not otherwise recognized, instead of
+    /// ignoring them.
+    pub fn with_raw_events(mut self) -> Self {
+        self.raw_events = true;
+        self
+    }
+
     /// Spawn a basic listener thread that keeps the API moving forward.
     /// Use this when you do not need to receive events from the Sengled API, such as when
     /// you are just sending a few messages to the API.
+    ///
+    /// With `with_auto_reconnect`, the listener reconnects whenever the connection drops.
+    /// Otherwise it stops at the first disconnect, which is logged with the `tracing` feature.
     pub fn spawn_listener(mut self, client: &mut Client) {
+        #[cfg(feature = "mock")]
+        if let (Some(state), Some(mut mock_events)) = (&mut client.state, self.mock_events.take()) {
+            state.listener_handle = Some(tokio::spawn(async move {
+                while mock_events.recv().await.is_some() {}
+            }));
+            return;
+        }
+
         if let Some(ref mut state) = client.state {
             state.listener_handle = Some(tokio::spawn(async move {
-                while let Ok(_event) = self.events.poll().await {
-                    // ...
+                let _error = loop {
+                    match self.poll().await {
+                        Ok(_) | Err(Error::Payload { .. }) => (),
+                        Err(error) => break error,
+                    }
+                };
+
+                #[cfg(feature = "tracing")]
+                if !self.closed {
+                    tracing::warn!(error = %_error, "listener stopped");
                 }
             }))
         }
     }
 
+    /// Turn the handler into a stream of events, which drives the event loop as it is
+    /// consumed. The stream ends once the connection is lost.
+    pub fn into_stream(self) -> impl Stream<Item = Result<Event, Error>> {
+        futures::stream::unfold(self, |mut handler| async move {
+            match handler.poll().await {
+                Err(Error::Disconnected) => None,
+                result => Some((result, handler)),
+            }
+        })
+    }
+
     pub async fn poll(&mut self) -> Result<Event, Error> {
+        // the connection is acknowledged during `start`, so report it on the first poll
+        if !self.connected {
+            self.connected = true;
+            return Ok(Event::Connected);
+        }
+
+        // the client was closed, so don't try to reconnect
+        if self.closed {
+            return Err(Error::Disconnected);
+        }
+
+        #[cfg(feature = "mock")]
+        if let Some(mock_events) = &mut self.mock_events {
+            let event = mock_events.recv().await.ok_or(Error::Disconnected)?;
+            Counters::increment(&self.counters.events_received);
+            return Ok(event);
+        }
+
+        if self.disconnected {
+            if let Some(policy) = self.reconnect_policy {
+                self.reconnect(policy).await;
+            }
+
+            self.disconnected = false;
+            return Ok(Event::Reconnected);
+        }
+
         loop {
-            match self.events.poll().await {
+            let event = self.events.poll().await;
+            if let Ok(event) = &event {
+                self.acks.observe(event);
+            }
+
+            match event {
                 Ok(MqttEvent::Incoming(Incoming::Publish(packet))) => {
-                    let status_regex = regex_macro::regex!("^wifielement/([0-9A-F:]+)/status$");
-                    let status_captures = match status_regex.captures(&packet.topic) {
-                        Some(captures) => captures,
+                    Counters::increment(&self.counters.events_received);
+
+                    // wildcard subscriptions deliver on each device's own topic, so the MAC
+                    // is always taken from the topic rather than the subscription
+                    let (source, mac) = match parse_status_topic(&packet.topic) {
+                        Some(device) => device,
+                        None if self.raw_events => {
+                            return Ok(Event::Raw {
+                                topic: packet.topic,
+                                payload: packet.payload.to_vec(),
+                            })
+                        }
                         None => continue,
                     };
 
-                    let mac = &status_captures[1];
-
-                    #[derive(Deserialize)]
-                    struct AttributesChangedPayload {
-                        #[serde(rename = "type")]
-                        name: String,
-                        value: String,
+                    let event =
+                        attributes_changed_event(source, mac, &packet.topic, &packet.payload);
+                    if event.is_err() {
+                        Counters::increment(&self.counters.errors);
                     }
+                    return event;
+                }
+                // the client was closed, so the connection going away is expected
+                Ok(MqttEvent::Outgoing(Outgoing::Disconnect)) => {
+                    self.closed = true;
+                    self.mqtt_connected.store(false, Ordering::Relaxed);
+                    return Err(Error::Disconnected);
+                }
+                Ok(MqttEvent::Incoming(Incoming::Disconnect)) | Err(_) => {
+                    self.mqtt_connected.store(false, Ordering::Relaxed);
+                    Counters::increment(&self.counters.errors);
 
-                    let attributes: Vec<AttributesChangedPayload> =
-                        serde_json::from_slice(&packet.payload).unwrap();
+                    if self.reconnect_policy.is_none() {
+                        return Err(Error::Disconnected);
+                    }
 
-                    return Ok(Event::DeviceAttributesChanged {
-                        device: String::from(mac),
-                        attributes: attributes
-                            .into_iter()
-                            .map(|AttributesChangedPayload { name, value }| (name, value))
-                            .collect::<Vec<_>>(),
-                    });
+                    self.disconnected = true;
+                    return Ok(Event::Disconnected);
                 }
-                Ok(MqttEvent::Incoming(Incoming::Disconnect)) => return Err(Error::Disconnected),
-                Err(_) => return Err(Error::Disconnected),
                 Ok(_) => (),
             }
         }
     }
+
+    /// Poll for the next event, discarding attribute changes from devices other than the
+    /// given ones. Events that are not about a device are always returned, and an empty
+    /// filter returns everything. MACs may be given in any form `normalize_mac` accepts.
+    pub async fn poll_filtered(&mut self, macs: &[String]) -> Result<Event, Error> {
+        let macs = macs
+            .iter()
+            .map(|mac| normalize_mac(mac))
+            .collect::<Vec<_>>();
+
+        loop {
+            match self.poll().await? {
+                Event::DeviceAttributesChanged { ref device, .. }
+                    if !macs.is_empty() && !macs.contains(device) => {}
+                event => return Ok(event),
+            }
+        }
+    }
+
+    /// Poll events until `device` reports `attribute` with the given value, or `timeout`
+    /// elapses, in which case `Error::AttributeTimeout` is returned. Every event observed
+    /// while waiting, including the matching one, is passed to `on_event` so that callers can
+    /// keep their own state up to date.
+    pub async fn wait_for_attribute(
+        &mut self,
+        device: impl AsDeviceMac,
+        attribute: &str,
+        value: &str,
+        timeout: Duration,
+        mut on_event: impl FnMut(&Event),
+    ) -> Result<(), Error> {
+        let mac = device.normalized_mac();
+
+        let wait = async {
+            loop {
+                let event = self.poll().await?;
+                on_event(&event);
+
+                if let Event::DeviceAttributesChanged {
+                    device, attributes, ..
+                } = &event
+                {
+                    if *device == mac
+                        && attributes
+                            .iter()
+                            .any(|(name, new_value)| name == attribute && new_value == value)
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        tokio::time::timeout(timeout, wait)
+            .await
+            .unwrap_or(Err(Error::AttributeTimeout))
+    }
+
+    /// Re-establish the MQTT connection, retrying until it succeeds.
+    async fn reconnect(&mut self, policy: ReconnectPolicy) {
+        let mut delay = policy.initial_delay;
+
+        loop {
+            tokio::time::sleep(policy.jittered_delay(delay, &self.config.jitter)).await;
+
+            if self.try_reconnect().await.is_ok() {
+                self.mqtt_connected.store(true, Ordering::Relaxed);
+                Counters::increment(&self.counters.reconnects);
+                return;
+            }
+
+            Counters::increment(&self.counters.errors);
+
+            delay = policy.next_delay(delay);
+        }
+    }
+
+    async fn try_reconnect(&mut self) -> Result<(), Error> {
+        self.events.clean();
+        self.acks.fail_pending();
+        let (server, _) = self.config.server().await?;
+        self.events.mqtt_options = self.config.mqtt_options(&server);
+        self.config.wait_for_connack(&mut self.events).await?;
+
+        let topics = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|topic| SubscribeFilter {
+                path: topic.to_owned(),
+                qos: self.config.qos,
+            })
+            .collect::<Vec<_>>();
+
+        if !topics.is_empty() {
+            self.mqtt.subscribe_many(topics).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Build the HTTP client used for requests to the Sengled API.
+fn build_http_client(
+    timeout: Option<Duration>,
+    proxy: Option<reqwest::Proxy>,
+) -> Result<reqwest::Client, reqwest::Error> {
+    let mut http = reqwest::Client::builder();
+
+    if let Some(timeout) = timeout {
+        http = http.timeout(timeout).connect_timeout(timeout);
+    }
+
+    if let Some(proxy) = proxy {
+        http = http.proxy(proxy);
+    }
+
+    http.build()
+}
+
+/// Check that a keep-alive interval is reasonable for the broker.
+fn validate_keep_alive(interval: Duration) -> Result<(), Error> {
+    if !(MIN_KEEP_ALIVE..=MAX_KEEP_ALIVE).contains(&interval) {
+        return Err(Error::OutOfRange {
+            value: interval.as_secs() as i64,
+            min: MIN_KEEP_ALIVE.as_secs() as i64,
+            max: MAX_KEEP_ALIVE.as_secs() as i64,
+        });
+    }
+
+    Ok(())
+}
+
+/// Status topics the event listener is subscribed to, by normalized device MAC. Topics are
+/// built from the MAC as the API gave it, since MQTT topics are case-sensitive.
+pub(crate) type Subscriptions = Arc<Mutex<HashMap<String, String>>>;
+
+/// The single-level MQTT wildcard, used in place of a MAC to subscribe to every device.
+const WILDCARD: &str = "+";
+
+/// The topic a device publishes its attribute changes to.
+fn status_topic(source: DeviceSource, mac: &str) -> String {
+    format!("{}/{}/status", source.topic_prefix(), mac)
+}
+
+/// The topic a device receives attribute updates on.
+fn update_topic(mac: &str) -> String {
+    format!("wifielement/{}/update", mac)
+}
+
+/// The current time in milliseconds since the epoch, as used in attribute updates.
+#[cfg(feature = "chrono")]
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// The current time in milliseconds since the epoch, as used in attribute updates.
+#[cfg(not(feature = "chrono"))]
+fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as i64)
+}
+
+/// The error for an API response missing the data it should have. A rejected session is
+/// reported with an error code, but the codes are undocumented, so only responses without a
+/// code, or whose message mentions the session or logging in, are taken to mean the session
+/// expired. Other codes are returned as `Error::Api`.
+fn api_error(body: &serde_json::Value) -> Error {
+    let code = match body.get("ret") {
+        Some(serde_json::Value::String(code)) => code.trim().parse().ok(),
+        Some(code) => code.as_i64(),
+        None => None,
+    };
+    let message = body
+        .get("msg")
+        .and_then(serde_json::Value::as_str)
+        .unwrap_or_default();
+
+    let lowercase = message.to_ascii_lowercase();
+    let mentions_session = ["session", "login"]
+        .iter()
+        .any(|word| lowercase.contains(word));
+
+    match code {
+        Some(code) if code != 0 && !mentions_session => Error::Api {
+            code,
+            message: message.to_owned(),
+        },
+        _ => Error::SessionExpired,
+    }
+}
+
+/// Get the source and MAC of the device a status topic belongs to. Some firmware reports
+/// lowercase MACs, so the MAC is returned in canonical form.
+fn parse_status_topic(topic: &str) -> Option<(DeviceSource, String)> {
+    let status_regex = regex_macro::regex!("^(wifielement|zigbee)/([0-9A-Fa-f:]+)/status$");
+    let captures = status_regex.captures(topic)?;
+
+    let source = match &captures[1] {
+        "zigbee" => DeviceSource::Zigbee,
+        _ => DeviceSource::Wifi,
+    };
+    Some((source, normalize_mac(&captures[2])))
+}
+
+/// Parse the payload of a device's status message into a `DeviceAttributesChanged` event.
+fn attributes_changed_event(
+    source: DeviceSource,
+    mac: String,
+    topic: &str,
+    payload: &[u8],
+) -> Result<Event, Error> {
+    #[derive(Deserialize)]
+    struct AttributesChangedPayload {
+        #[serde(rename = "type")]
+        name: String,
+        value: String,
+
+        // sent as either a number or a string of one
+        #[serde(default)]
+        time: Option<serde_json::Value>,
+    }
+
+    let attributes: Vec<AttributesChangedPayload> =
+        serde_json::from_slice(payload).map_err(|_| Error::Payload {
+            topic: topic.to_owned(),
+            snippet: payload_snippet(payload),
+        })?;
+
+    let times = attributes
+        .iter()
+        .filter_map(|attribute| {
+            let time = match attribute.time.as_ref()? {
+                serde_json::Value::String(time) => time.trim().parse().ok(),
+                time => time.as_i64(),
+            };
+            Some((attribute.name.to_owned(), time?))
+        })
+        .collect();
+
+    Ok(Event::DeviceAttributesChanged {
+        device: mac,
+        source,
+        attributes: attributes
+            .into_iter()
+            .map(|AttributesChangedPayload { name, value, .. }| (name, value))
+            .collect::<Vec<_>>(),
+        times,
+    })
+}
+
+/// Truncate a payload to a short, printable snippet for error messages.
+fn payload_snippet(payload: &[u8]) -> String {
+    const MAX_LEN: usize = 128;
+
+    let text = String::from_utf8_lossy(payload);
+    match text.char_indices().nth(MAX_LEN) {
+        Some((index, _)) => format!("{}...", &text[..index]),
+        None => text.into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn methods_on_a_fresh_client_are_not_started() {
+        let client = Client::new("user", "password");
+
+        assert!(matches!(
+            client.wifi_devices().await,
+            Err(Error::NotStarted)
+        ));
+        assert!(matches!(
+            client.subscribe_device("B0:CE:18:00:00:01").await,
+            Err(Error::NotStarted)
+        ));
+        assert!(matches!(
+            client
+                .set_device_attribute("B0:CE:18:00:00:01", "switch", "1")
+                .await,
+            Err(Error::NotStarted)
+        ));
+    }
+
+    #[tokio::test]
+    async fn starting_without_a_session_is_an_error() {
+        let mut client = Client::new("user", "password");
+        assert!(matches!(client.start().await, Err(Error::NoSession)));
+    }
+
+    #[test]
+    fn bogus_payloads_are_payload_errors() {
+        const TOPIC: &str = "wifielement/B0:CE:18:00:00:01/status";
+        let long = "x".repeat(1000);
+
+        for payload in [&b"not json"[..], br#"{"type":"switch"}"#, long.as_bytes()] {
+            let event = attributes_changed_event(
+                DeviceSource::Wifi,
+                String::from("B0:CE:18:00:00:01"),
+                TOPIC,
+                payload,
+            );
+            match event {
+                Err(Error::Payload { topic, snippet }) => {
+                    assert_eq!(topic, TOPIC);
+                    assert!(snippet.len() <= 131);
+                }
+                _ => panic!("expected a payload error"),
+            }
+        }
+    }
+
+    #[test]
+    fn status_payloads_parse_into_attribute_changes() {
+        let event = attributes_changed_event(
+            DeviceSource::Wifi,
+            String::from("B0:CE:18:00:00:01"),
+            "wifielement/B0:CE:18:00:00:01/status",
+            br#"[{"type":"switch","value":"1","time":"1700000000000"}]"#,
+        );
+        match event {
+            Ok(Event::DeviceAttributesChanged {
+                attributes, times, ..
+            }) => {
+                assert_eq!(
+                    attributes,
+                    vec![(String::from("switch"), String::from("1"))]
+                );
+                assert_eq!(times.get("switch"), Some(&1700000000000));
+            }
+            _ => panic!("expected a DeviceAttributesChanged event"),
+        }
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn unsubscribing_uses_the_status_topics() {
+        let (mut client, mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+
+        client
+            .subscribe_devices(&["B0:CE:18:00:00:01", "B0:CE:18:00:00:02"])
+            .await
+            .unwrap();
+        client
+            .unsubscribe_device("B0:CE:18:00:00:01")
+            .await
+            .unwrap();
+        client
+            .unsubscribe_devices(&["B0:CE:18:00:00:02"])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            mock.unsubscribed(),
+            vec![
+                String::from("wifielement/B0:CE:18:00:00:01/status"),
+                String::from("wifielement/B0:CE:18:00:00:02/status"),
+            ]
+        );
+        assert!(client.subscribed_devices().is_empty());
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn the_server_address_is_recorded_on_start() {
+        let (mut client, _mock) = Client::new_mock();
+        assert_eq!(client.server_address(), None);
+
+        let _event_handler = client.start().await.unwrap();
+        let expected = Url::parse(Region::Us.mqtt_url()).unwrap();
+        assert_eq!(client.server_address(), Some(expected.as_str()));
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn get_device_filters_the_device_list() {
+        let (mut client, mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+        mock.set_devices(vec![
+            Device::test_bulb("B0:CE:18:00:00:01", &[("switch", "0")]),
+            Device::test_bulb("B0:CE:18:00:00:02", &[("switch", "1")]),
+        ]);
+
+        let device = client.get_device("b0:ce:18:00:00:02").await.unwrap();
+        assert_eq!(device.mac, "B0:CE:18:00:00:02");
+        assert_eq!(device.is_on(), Some(true));
+
+        match client.get_device("B0:CE:18:00:00:03").await {
+            Err(Error::DeviceNotFound(mac)) => assert_eq!(mac, "B0:CE:18:00:00:03"),
+            _ => panic!("expected DeviceNotFound"),
+        }
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn subscriptions_use_the_preferred_qos() {
+        let (client, mock) = Client::new_mock();
+        let mut client = client.with_preferred_qos(QoS::AtLeastOnce);
+        let _event_handler = client.start().await.unwrap();
+
+        client.subscribe_device("B0:CE:18:00:00:01").await.unwrap();
+        client
+            .subscribe_devices(&["B0:CE:18:00:00:02"])
+            .await
+            .unwrap();
+
+        let subscribed = mock.subscribed();
+        assert_eq!(subscribed.len(), 2);
+        assert!(subscribed.iter().all(|(_, qos)| *qos == QoS::AtLeastOnce));
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn rejected_credentials_are_login_failures() {
+        let (mut client, mock) = Client::new_mock();
+        client.set_session("previous");
+        mock.respond_with(
+            "/AuthenCross.json",
+            200,
+            json!({ "ret": 100, "msg": "password error" }),
+        );
+
+        match client.login().await {
+            Err(Error::LoginFailed { code, message }) => {
+                assert_eq!(code, 100);
+                assert_eq!(message, "password error");
+            }
+            _ => panic!("expected LoginFailed"),
+        }
+        assert_eq!(client.session(), Some("previous"));
+
+        client.login().await.unwrap();
+        assert_eq!(client.session(), Some("mock"));
+    }
+
+    #[cfg(feature = "mock")]
+    fn switch_changed(mac: &str) -> Event {
+        Event::DeviceAttributesChanged {
+            device: String::from(mac),
+            attributes: vec![(String::from("switch"), String::from("1"))],
+            source: DeviceSource::Wifi,
+            times: Default::default(),
+        }
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn the_event_stream_yields_events_until_disconnected() {
+        use futures::StreamExt;
+
+        let (mut client, mock) = Client::new_mock();
+        let event_handler = client.start().await.unwrap();
+
+        mock.push_event(switch_changed("B0:CE:18:00:00:01"));
+        mock.push_event(switch_changed("B0:CE:18:00:00:02"));
+        drop((client, mock));
+
+        let events = event_handler
+            .into_stream()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(events.len(), 3);
+        assert!(matches!(events[0], Event::Connected));
+        assert!(matches!(
+            &events[2],
+            Event::DeviceAttributesChanged { device, .. } if device == "B0:CE:18:00:00:02"
+        ));
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn poll_filtered_only_yields_the_given_devices() {
+        let (mut client, mock) = Client::new_mock();
+        let mut event_handler = client.start().await.unwrap();
+        let filter = [String::from("b0ce18000002")];
+
+        assert!(matches!(
+            event_handler.poll_filtered(&filter).await.unwrap(),
+            Event::Connected
+        ));
+
+        mock.push_event(switch_changed("B0:CE:18:00:00:01"));
+        mock.push_event(switch_changed("B0:CE:18:00:00:02"));
+        assert!(matches!(
+            event_handler.poll_filtered(&filter).await.unwrap(),
+            Event::DeviceAttributesChanged { device, .. } if device == "B0:CE:18:00:00:02"
+        ));
+
+        mock.push_event(switch_changed("B0:CE:18:00:00:01"));
+        assert!(matches!(
+            event_handler.poll_filtered(&[]).await.unwrap(),
+            Event::DeviceAttributesChanged { device, .. } if device == "B0:CE:18:00:00:01"
+        ));
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn lenient_device_lists_collect_malformed_devices() {
+        let (mut client, mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+        mock.respond_with(
+            "/device/list.json",
+            200,
+            json!({
+                "ret": 0,
+                "deviceList": [
+                    {
+                        "category": "wifielement",
+                        "deviceUuid": "B0:CE:18:00:00:01",
+                        "typeCode": "W21-N13",
+                        "attributeList": [],
+                    },
+                    { "deviceUuid": "B0:CE:18:00:00:02" },
+                ],
+            }),
+        );
+
+        let (devices, failed) = client.wifi_devices_lenient().await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].mac, "B0:CE:18:00:00:01");
+        assert_eq!(failed, vec![json!({ "deviceUuid": "B0:CE:18:00:00:02" })]);
+    }
+
+    #[tokio::test]
+    async fn login_goes_through_the_http_proxy() {
+        use tokio::io::AsyncReadExt;
+
+        let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("http://{}", proxy.local_addr().unwrap());
+
+        // read the proxy request, then hang up without tunnelling it
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = proxy.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buf = [0; 1024];
+                let read = stream.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            String::from_utf8(request).unwrap()
+        });
+
+        let mut client =
+            Client::new("user", "password").with_proxy(reqwest::Proxy::all(proxy_url).unwrap());
+        assert!(client.login().await.is_err());
+
+        let request = request.await.unwrap();
+        assert!(
+            request.starts_with("CONNECT ucenter.cloud.sengled.com:443 "),
+            "{request}"
+        );
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn publishes_can_override_the_preferred_qos() {
+        let (mut client, mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+
+        client
+            .set_device_attribute("B0:CE:18:00:00:01", "brightness", "40")
+            .await
+            .unwrap();
+        client
+            .set_device_attribute_with_qos("B0:CE:18:00:00:01", "switch", "0", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        let qos = mock
+            .published_with_qos()
+            .into_iter()
+            .map(|(_, _, qos)| qos)
+            .collect::<Vec<_>>();
+        assert_eq!(qos, vec![QoS::AtMostOnce, QoS::AtLeastOnce]);
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (client, mock) = Client::new_mock();
+        let mut client = client.with_http_retries(2, Duration::from_millis(1));
+        let _event_handler = client.start().await.unwrap();
+        mock.set_devices(vec![Device::test_bulb("B0:CE:18:00:00:01", &[])]);
+        mock.respond_with("/device/list.json", 503, json!({}));
+        mock.respond_with("/device/list.json", 502, json!({}));
+
+        assert_eq!(client.wifi_devices().await.unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn rejected_logins_are_not_retried() {
+        let (client, mock) = Client::new_mock();
+        let mut client = client.with_http_retries(2, Duration::from_millis(1));
+        mock.respond_with(
+            "/AuthenCross.json",
+            400,
+            json!({ "ret": 100, "msg": "password error" }),
+        );
+
+        assert!(matches!(
+            client.login().await,
+            Err(Error::LoginFailed { code: 100, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn an_unresponsive_broker_times_out() {
+        const TIMEOUT: Duration = Duration::from_millis(200);
+
+        // accept the connection, then never answer it
+        let broker = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("wss://{}/mqtt", broker.local_addr().unwrap());
+        let (accepted_tx, accepted_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (_stream, _) = broker.accept().await.unwrap();
+            let _ = accepted_tx.send(());
+            std::future::pending::<()>().await
+        });
+
+        let mut client = Client::new("user", "password")
+            .with_server_url(url)
+            .with_connect_timeout(TIMEOUT);
+        client.set_session("session");
+
+        let started = tokio::time::Instant::now();
+        assert!(matches!(
+            client.start().await,
+            Err(Error::ConnectionFailure)
+        ));
+        assert!(started.elapsed() >= TIMEOUT);
+        assert!(accepted_rx.await.is_ok());
+    }
+
+    #[test]
+    fn lowercase_status_topics_are_recognized() {
+        assert_eq!(
+            parse_status_topic("wifielement/b0:ce:18:0a:bc:01/status"),
+            Some((DeviceSource::Wifi, String::from("B0:CE:18:0A:BC:01")))
+        );
+        assert_eq!(
+            parse_status_topic("zigbee/B0:CE:18:0A:BC:02/status"),
+            Some((DeviceSource::Zigbee, String::from("B0:CE:18:0A:BC:02")))
+        );
+        assert_eq!(
+            parse_status_topic("wifielement/b0:ce:18:0a:bc:01/update"),
+            None
+        );
+        assert_eq!(parse_status_topic("wifielement/xyz/status"), None);
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn unchanged_attributes_are_not_published() {
+        let (mut client, mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+        let bulb = Device::test_bulb("B0:CE:18:00:00:01", &[("brightness", "100")]);
+
+        assert!(!client
+            .set_device_attribute_if_changed(&bulb, "brightness", "100")
+            .await
+            .unwrap());
+        assert!(mock.published().is_empty());
+
+        assert!(client
+            .set_device_attribute_if_changed(&bulb, "brightness", "50")
+            .await
+            .unwrap());
+        assert_eq!(mock.published().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_failed_start_leaves_the_client_stopped() {
+        let mut client = Client::new("user", "password");
+        assert!(!client.is_started());
+        assert!(!client.is_connected());
+
+        assert!(client.start().await.is_err());
+        assert!(!client.is_started());
+        assert!(!client.is_connected());
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn starting_marks_the_client_started_and_connected() {
+        let (mut client, _mock) = Client::new_mock();
+        assert!(!client.is_started());
+
+        let _event_handler = client.start().await.unwrap();
+        assert!(client.is_started());
+        assert!(client.is_connected());
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn wait_for_attribute_returns_on_the_matching_change() {
+        let (mut client, mock) = Client::new_mock();
+        let mut event_handler = client.start().await.unwrap();
+
+        mock.push_event(switch_changed("B0:CE:18:00:00:02"));
+        mock.push_event(switch_changed("B0:CE:18:00:00:01"));
+
+        let mut observed = 0;
+        event_handler
+            .wait_for_attribute(
+                "b0:ce:18:00:00:01",
+                "switch",
+                "1",
+                Duration::from_secs(5),
+                |_| observed += 1,
+            )
+            .await
+            .unwrap();
+        assert_eq!(observed, 3);
+
+        assert!(matches!(
+            event_handler
+                .wait_for_attribute(
+                    "B0:CE:18:00:00:01",
+                    "switch",
+                    "0",
+                    Duration::from_millis(50),
+                    |_| (),
+                )
+                .await,
+            Err(Error::AttributeTimeout)
+        ));
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn dropping_the_client_aborts_the_listener() {
+        let (mut client, _mock) = Client::new_mock();
+        client.start().await.unwrap().spawn_listener(&mut client);
+
+        let listener = client
+            .state
+            .as_ref()
+            .and_then(|state| state.listener_handle.as_ref())
+            .unwrap()
+            .abort_handle();
+        assert!(!listener.is_finished());
+
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !listener.is_finished() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn attribute_changes_are_typed() {
+        let event = Event::DeviceAttributesChanged {
+            device: String::from("B0:CE:18:00:00:01"),
+            attributes: [
+                ("switch", "1"),
+                ("brightness", "300"),
+                ("color", "255:128:0"),
+                ("colorTemperature", "40"),
+                ("colorMode", "3"),
+                ("online", "0"),
+                ("colorTemperature", "140"),
+                ("consumptionTime", "12"),
+            ]
+            .iter()
+            .map(|(name, value)| (String::from(*name), String::from(*value)))
+            .collect(),
+            source: DeviceSource::Wifi,
+            times: Default::default(),
+        };
+
+        assert_eq!(
+            event.typed_changes(),
+            vec![
+                TypedChange::Switch(true),
+                TypedChange::Brightness(255),
+                TypedChange::Color(Color::new(255, 128, 0)),
+                TypedChange::ColorTemperature(40),
+                TypedChange::ColorMode(ColorMode::Rhythm),
+                TypedChange::Online(false),
+                TypedChange::Raw {
+                    attribute: String::from("colorTemperature"),
+                    value: String::from("140"),
+                },
+                TypedChange::Raw {
+                    attribute: String::from("consumptionTime"),
+                    value: String::from("12"),
+                },
+            ]
+        );
+        assert!(Event::Connected.typed_changes().is_empty());
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn raw_publishes_are_forwarded_unchanged() {
+        let (mut client, mock) = Client::new_mock();
+        assert!(matches!(
+            client
+                .publish_raw("group/1/update", "{}", QoS::AtMostOnce, false)
+                .await,
+            Err(Error::NotStarted)
+        ));
+
+        let _event_handler = client.start().await.unwrap();
+        client
+            .publish_raw(
+                "group/1/update",
+                r#"{"switch":"1"}"#,
+                QoS::AtLeastOnce,
+                false,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            mock.published_with_qos(),
+            vec![(
+                String::from("group/1/update"),
+                String::from(r#"{"switch":"1"}"#),
+                QoS::AtLeastOnce
+            )]
+        );
+    }
+
+    #[test]
+    fn attribute_times_are_optional() {
+        let event = attributes_changed_event(
+            DeviceSource::Wifi,
+            String::from("B0:CE:18:00:00:01"),
+            "wifielement/B0:CE:18:00:00:01/status",
+            br#"[
+                {"type":"switch","value":"1","time":1700000000000},
+                {"type":"brightness","value":"40","time":"1700000000001"},
+                {"type":"online","value":"1"}
+            ]"#,
+        );
+
+        match event {
+            Ok(Event::DeviceAttributesChanged {
+                attributes, times, ..
+            }) => {
+                assert_eq!(attributes.len(), 3);
+                assert_eq!(times.get("switch"), Some(&1700000000000));
+                assert_eq!(times.get("brightness"), Some(&1700000000001));
+                assert_eq!(times.get("online"), None);
+            }
+            _ => panic!("expected a DeviceAttributesChanged event"),
+        }
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn refreshing_a_device_overwrites_its_attributes() {
+        let (mut client, mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+        mock.set_devices(vec![Device::test_bulb(
+            "B0:CE:18:00:00:01",
+            &[("switch", "1"), ("brightness", "80")],
+        )]);
+
+        let mut bulb = Device::test_bulb("B0:CE:18:00:00:01", &[("switch", "0"), ("stale", "1")]);
+        client.refresh_device_state(&mut bulb).await.unwrap();
+        assert_eq!(bulb.is_on(), Some(true));
+        assert_eq!(bulb.brightness(), Some(80));
+        assert_eq!(bulb.get_attribute("stale"), None);
+
+        mock.set_devices(Vec::new());
+        assert!(matches!(
+            client.refresh_device_state(&mut bulb).await,
+            Err(Error::DeviceNotFound(_))
+        ));
+        assert_eq!(bulb.is_on(), Some(true));
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn batched_updates_keep_the_given_times() {
+        let (mut client, mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+
+        let before = now_millis();
+        client
+            .set_device_attributes_at(
+                "B0:CE:18:00:00:01",
+                &[
+                    ("switch", "1", Some(1600000000000)),
+                    ("brightness", "40", None),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let published = mock.published();
+        let body: serde_json::Value = serde_json::from_str(&published[0].1).unwrap();
+        assert_eq!(body[0]["type"], "switch");
+        assert_eq!(body[0]["time"], 1600000000000i64);
+        assert_eq!(body[1]["type"], "brightness");
+        assert!(body[1]["time"].as_i64().unwrap() >= before);
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn subscribing_to_all_uses_the_wildcard_topic() {
+        let (mut client, mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+
+        client.subscribe_all().await.unwrap();
+        assert_eq!(
+            mock.subscribed()
+                .into_iter()
+                .map(|(topic, _)| topic)
+                .collect::<Vec<_>>(),
+            vec![String::from("wifielement/+/status")]
+        );
+        assert!(client.subscribed_devices().is_empty());
+
+        // events on wildcard-matched topics come from each device's own topic
+        assert_eq!(
+            parse_status_topic("wifielement/B0:CE:18:00:00:01/status"),
+            Some((DeviceSource::Wifi, String::from("B0:CE:18:00:00:01")))
+        );
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn dry_runs_skip_publishes() {
+        let (client, mock) = Client::new_mock();
+        let mut client = client.with_dry_run();
+        let _event_handler = client.start().await.unwrap();
+
+        client
+            .set_device_attribute("B0:CE:18:00:00:01", "switch", "1")
+            .await
+            .unwrap();
+        client
+            .set_device_attribute_with_qos("B0:CE:18:00:00:01", "switch", "0", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        client
+            .publish_raw(
+                "wifielement/B0:CE:18:00:00:01/update",
+                "[]",
+                QoS::AtMostOnce,
+                false,
+            )
+            .await
+            .unwrap();
+
+        assert!(mock.published().is_empty());
+
+        // everything except publishes still works
+        client.subscribe_device("B0:CE:18:00:00:01").await.unwrap();
+        assert_eq!(mock.subscribed().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn server_urls_without_a_host_are_rejected_on_start() {
+        for url in ["foo:bar", "http://mqtt.example.com/mqtt"] {
+            let mut client = Client::new("user", "password").with_server_url(url);
+            client.set_session("session");
+
+            assert!(matches!(
+                client.start().await,
+                Err(Error::InvalidServerUrl { .. })
+            ));
+        }
+    }
+
+    // the field values of a span
+    #[cfg(feature = "tracing")]
+    type SpanFields = Vec<(&'static str, String)>;
+
+    // records the fields of every span created while it is the default subscriber
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<Mutex<Vec<(&'static str, SpanFields)>>>);
+
+    #[cfg(feature = "tracing")]
+    impl SpanRecorder {
+        fn fields(&self, name: &str) -> Vec<SpanFields> {
+            self.0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(span, _)| *span == name)
+                .map(|(_, fields)| fields.clone())
+                .collect()
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            struct Fields(SpanFields);
+
+            impl tracing::field::Visit for Fields {
+                fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                    self.0.push((field.name(), value.to_owned()));
+                }
+
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    self.0.push((field.name(), format!("{:?}", value)));
+                }
+            }
+
+            let mut fields = Fields(Vec::new());
+            span.record(&mut fields);
+
+            let mut spans = self.0.lock().unwrap();
+            spans.push((span.metadata().name(), fields.0));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(all(feature = "tracing", feature = "mock"))]
+    #[tokio::test]
+    async fn publish_spans_record_their_fields() {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+
+        let (mut client, _mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+
+        client
+            .set_device_attribute("B0:CE:18:00:00:01", "brightness", "40")
+            .await
+            .unwrap();
+        client
+            .publish_raw(
+                "wifielement/B0:CE:18:00:00:01/update",
+                "[]",
+                QoS::AtMostOnce,
+                false,
+            )
+            .await
+            .unwrap();
+
+        let spans = recorder.fields("sengled::publish");
+        assert_eq!(
+            spans[0],
+            vec![
+                ("device", String::from("B0:CE:18:00:00:01")),
+                ("attribute", String::from("brightness")),
+            ]
+        );
+        assert_eq!(
+            spans[1],
+            vec![(
+                "topic",
+                String::from("wifielement/B0:CE:18:00:00:01/update")
+            )]
+        );
+    }
+
+    #[test]
+    fn builders_reject_a_server_url_with_a_region() {
+        let built = Client::builder("user", "password")
+            .server_url("wss://mqtt.example.com/mqtt")
+            .region(Region::Eu)
+            .build();
+
+        assert!(matches!(
+            built,
+            Err(Error::IncompatibleOptions("server_url", "region"))
+        ));
+    }
+
+    #[test]
+    fn builders_reject_a_server_url_with_skip_server_check() {
+        let built = Client::builder("user", "password")
+            .server_url("wss://mqtt.example.com/mqtt")
+            .skip_server_check()
+            .build();
+
+        assert!(matches!(
+            built,
+            Err(Error::IncompatibleOptions(
+                "server_url",
+                "skip_server_check"
+            ))
+        ));
+    }
+
+    #[test]
+    fn builders_reject_an_http_client_with_a_timeout() {
+        let built = Client::builder("user", "password")
+            .http_client(reqwest::Client::new())
+            .http_timeout(Duration::from_secs(5))
+            .build();
+
+        assert!(matches!(
+            built,
+            Err(Error::IncompatibleOptions("http_client", "http_timeout"))
+        ));
+    }
+
+    #[test]
+    fn builders_reject_an_http_client_with_a_proxy() {
+        let built = Client::builder("user", "password")
+            .http_client(reqwest::Client::new())
+            .proxy(reqwest::Proxy::all("http://127.0.0.1:8080").unwrap())
+            .build();
+
+        assert!(matches!(
+            built,
+            Err(Error::IncompatibleOptions("http_client", "proxy"))
+        ));
+    }
+
+    #[test]
+    fn builders_reject_keep_alives_out_of_range() {
+        for interval in [
+            Duration::from_secs(4),
+            MAX_KEEP_ALIVE + Duration::from_secs(1),
+        ] {
+            let built = Client::builder("user", "password")
+                .keep_alive(interval)
+                .build();
+
+            assert!(matches!(
+                built,
+                Err(Error::OutOfRange {
+                    min: 5,
+                    max: 65535,
+                    ..
+                })
+            ));
+        }
+
+        assert!(Client::builder("user", "password")
+            .keep_alive(MIN_KEEP_ALIVE)
+            .build()
+            .is_ok());
+    }
 }
@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A snapshot of the client's counters, see `Client::metrics`. Counters start at zero when
+/// the client is started and only ever increase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Publishes queued for the MQTT server, including bulk and raw publishes.
+    pub publishes_sent: u64,
+
+    /// Messages received from the MQTT server on subscribed topics.
+    pub events_received: u64,
+
+    /// Times the event handler reconnected after the connection dropped.
+    pub reconnects: u64,
+
+    /// Failed publishes, unparseable payloads, connection drops and failed reconnect attempts.
+    pub errors: u64,
+}
+
+/// The live counters behind `Metrics`, shared by the client and its event handler.
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) publishes_sent: AtomicU64,
+    pub(crate) events_received: AtomicU64,
+    pub(crate) reconnects: AtomicU64,
+    pub(crate) errors: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Metrics {
+        Metrics {
+            publishes_sent: self.publishes_sent.load(Ordering::Relaxed),
+            events_received: self.events_received.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_read_the_counters() {
+        let counters = Counters::default();
+        Counters::increment(&counters.publishes_sent);
+        Counters::increment(&counters.publishes_sent);
+        Counters::increment(&counters.errors);
+
+        assert_eq!(
+            counters.snapshot(),
+            Metrics {
+                publishes_sent: 2,
+                errors: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn operations_increment_the_counters() {
+        let (mut client, mock) = crate::Client::new_mock();
+        assert_eq!(client.metrics(), Metrics::default());
+
+        let mut event_handler = client.start().await.unwrap();
+        client
+            .set_device_attribute("B0:CE:18:00:00:01", "switch", "1")
+            .await
+            .unwrap();
+        client
+            .set_device_attributes("B0:CE:18:00:00:01", &[("brightness", "40")])
+            .await
+            .unwrap();
+
+        mock.push_event(crate::Event::Connected);
+        event_handler.poll().await.unwrap();
+        event_handler.poll().await.unwrap();
+
+        let metrics = client.metrics();
+        assert_eq!(metrics.publishes_sent, 2);
+        assert_eq!(metrics.events_received, 1);
+        assert_eq!(metrics.reconnects, 0);
+    }
+}
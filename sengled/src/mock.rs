@@ -0,0 +1,337 @@
+use std::{
+    collections::VecDeque,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
+
+use rumqttc::{AsyncClient as MqttClient, EventLoop, QoS, Request};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::{
+    ack::AckTracker,
+    connection::{ConnectionConfig, MqttServer},
+    metrics::Counters,
+    ClientState, Device, Error, Event, EventHandler, Subscriptions,
+};
+
+/// A handle to the fake Sengled cloud behind a client made with `Client::new_mock`, which
+/// never touches the network. Use it to give the client devices, push events to its
+/// `EventHandler` and see what it published.
+///
+/// ```
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), sengled::Error> {
+/// let (mut client, mock) = sengled::Client::new_mock();
+/// let mut event_handler = client.start().await?;
+///
+/// mock.push_event(sengled::Event::DeviceAttributesChanged {
+///     device: String::from("B0:CE:18:00:00:01"),
+///     attributes: vec![(String::from("switch"), String::from("1"))],
+///     source: sengled::DeviceSource::Wifi,
+///     times: Default::default(),
+/// });
+///
+/// assert!(matches!(event_handler.poll().await?, sengled::Event::Connected));
+/// match event_handler.poll().await? {
+///     sengled::Event::DeviceAttributesChanged { device, .. } => {
+///         assert_eq!(device, "B0:CE:18:00:00:01")
+///     }
+///     _ => unreachable!(),
+/// }
+///
+/// client.set_device_attribute("B0:CE:18:00:00:01", "switch", "0").await?;
+/// assert_eq!(mock.published().len(), 1);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MockTransport {
+    devices: Arc<Mutex<Vec<Device>>>,
+    responses: Arc<Mutex<VecDeque<(String, u16, Value)>>>,
+    events: mpsc::UnboundedSender<Event>,
+    requests: flume::Receiver<Request>,
+    log: Arc<Mutex<RequestLog>>,
+}
+
+/// MQTT requests taken from the client but not yet returned by `MockTransport`.
+#[derive(Default)]
+struct RequestLog {
+    published: Vec<(String, String, QoS)>,
+    subscribed: Vec<(String, QoS)>,
+    unsubscribed: Vec<String>,
+}
+
+/// The client's side of a `MockTransport`.
+pub(crate) struct MockBackend {
+    transport: MockTransport,
+    events: Option<mpsc::UnboundedReceiver<Event>>,
+    requests: flume::Sender<Request>,
+}
+
+impl MockTransport {
+    pub(crate) fn new() -> (Self, MockBackend) {
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let (requests_tx, requests_rx) = flume::unbounded();
+
+        let transport = Self {
+            devices: Arc::new(Mutex::new(Vec::new())),
+            responses: Arc::new(Mutex::new(VecDeque::new())),
+            events: events_tx,
+            requests: requests_rx,
+            log: Arc::new(Mutex::new(RequestLog::default())),
+        };
+
+        let backend = MockBackend {
+            transport: transport.clone(),
+            events: Some(events_rx),
+            requests: requests_tx,
+        };
+
+        (transport, backend)
+    }
+
+    /// Set the devices returned by `wifi_devices` and the methods built on it.
+    pub fn set_devices(&self, devices: Vec<Device>) {
+        *self.devices.lock().unwrap() = devices;
+    }
+
+    /// Queue a response for the next request to an HTTP route ending in `route`, such as
+    /// `/device/list.json`, to simulate failures or payloads `set_devices` can't express.
+    /// Queued responses are used once each, in order, before the default answer.
+    pub fn respond_with(&self, route: &str, status: u16, body: Value) {
+        self.responses
+            .lock()
+            .unwrap()
+            .push_back((String::from(route), status, body));
+    }
+
+    /// Queue an event to be returned by the client's `EventHandler::poll`.
+    pub fn push_event(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+
+    /// Take the topics and payloads published by the client since the last call, in order.
+    pub fn published(&self) -> Vec<(String, String)> {
+        self.published_with_qos()
+            .into_iter()
+            .map(|(topic, payload, _)| (topic, payload))
+            .collect()
+    }
+
+    /// Like `published`, with the QoS of each publish.
+    pub fn published_with_qos(&self) -> Vec<(String, String, QoS)> {
+        std::mem::take(&mut self.drain().published)
+    }
+
+    /// Take the topics subscribed to by the client since the last call, with their QoS, in
+    /// order.
+    pub fn subscribed(&self) -> Vec<(String, QoS)> {
+        std::mem::take(&mut self.drain().subscribed)
+    }
+
+    /// Take the topics unsubscribed from by the client since the last call, in order.
+    pub fn unsubscribed(&self) -> Vec<String> {
+        std::mem::take(&mut self.drain().unsubscribed)
+    }
+
+    /// Sort the client's queued MQTT requests into the log.
+    fn drain(&self) -> std::sync::MutexGuard<'_, RequestLog> {
+        let mut log = self.log.lock().unwrap();
+
+        for request in self.requests.try_iter() {
+            match request {
+                Request::Publish(publish) => log.published.push((
+                    publish.topic,
+                    String::from_utf8_lossy(&publish.payload).into_owned(),
+                    publish.qos,
+                )),
+                Request::Subscribe(subscribe) => log.subscribed.extend(
+                    subscribe
+                        .filters
+                        .into_iter()
+                        .map(|filter| (filter.path, filter.qos)),
+                ),
+                Request::Unsubscribe(unsubscribe) => log.unsubscribed.extend(unsubscribe.topics),
+                _ => (),
+            }
+        }
+
+        log
+    }
+
+    /// Answer a request to the HTTP API with the next queued response for its route, or as
+    /// the Sengled cloud would if it accepted it.
+    pub(crate) fn respond(&self, url: &str) -> http::Response<String> {
+        let mut responses = self.responses.lock().unwrap();
+        if let Some(index) = responses
+            .iter()
+            .position(|(route, _, _)| url.ends_with(route.as_str()))
+        {
+            let (_, status, body) = responses.remove(index).unwrap();
+            let mut response = http::Response::new(body.to_string());
+            *response.status_mut() = http::StatusCode::from_u16(status).unwrap();
+            return response;
+        }
+
+        http::Response::new(self.default_response(url).to_string())
+    }
+
+    fn default_response(&self, url: &str) -> Value {
+        if url.ends_with("/AuthenCross.json") {
+            json!({ "ret": 0, "jsessionId": "mock" })
+        } else if url.ends_with("/device/list.json") {
+            json!({ "ret": 0, "deviceList": *self.devices.lock().unwrap() })
+        } else if url.ends_with("/getDeviceDetails.json") {
+            json!({ "ret": 0, "deviceInfos": [] })
+        } else if url.ends_with("/room/list.json") {
+            json!({ "ret": 0, "roomList": [] })
+        } else {
+            json!({ "ret": 0 })
+        }
+    }
+}
+
+impl MockBackend {
+    pub(crate) fn transport(&self) -> &MockTransport {
+        &self.transport
+    }
+
+    /// Start the client without connecting, wiring its MQTT requests to the transport.
+    pub(crate) fn start(
+        &mut self,
+        config: ConnectionConfig,
+    ) -> Result<(ClientState, EventHandler), Error> {
+        let server = MqttServer::parse(config.region.mqtt_url())?;
+        let client = MqttClient::from_senders(self.requests.clone());
+
+        // the event loop is never polled, the handler reads the pushed events instead
+        let events = EventLoop::new(config.mqtt_options(&server), config.mqtt_capacity);
+
+        let subscriptions = Subscriptions::default();
+        let counters = Arc::new(Counters::default());
+        let acks = Arc::new(AckTracker::new(
+            config.rate_limit,
+            config.dry_run,
+            Arc::clone(&counters),
+        ));
+        let mqtt_connected = Arc::new(AtomicBool::new(true));
+
+        Ok((
+            ClientState {
+                mqtt: client.clone(),
+                listener_handle: None,
+                subscriptions: Arc::clone(&subscriptions),
+                server_url: server.url,
+                server_info: None,
+                acks: Arc::clone(&acks),
+                mqtt_connected: Arc::clone(&mqtt_connected),
+                counters: Arc::clone(&counters),
+                session: Arc::clone(&config.session),
+            },
+            EventHandler {
+                events,
+                mqtt: client,
+                config,
+                subscriptions,
+                acks,
+                mqtt_connected,
+                counters,
+                reconnect_policy: None,
+                raw_events: false,
+                connected: false,
+                disconnected: false,
+                closed: false,
+                mock_events: Some(
+                    self.events
+                        .take()
+                        .unwrap_or_else(|| mpsc::unbounded_channel().1),
+                ),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Client, DeviceSource};
+
+    #[tokio::test]
+    async fn lists_the_canned_devices() {
+        let (mut client, mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+        mock.set_devices(vec![Device::test_bulb("B0:CE:18:00:00:01", &[])]);
+
+        let devices = client.wifi_devices().await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].mac, "B0:CE:18:00:00:01");
+    }
+
+    #[tokio::test]
+    async fn drives_the_event_handler_with_pushed_events() {
+        let (mut client, mock) = Client::new_mock();
+        let mut event_handler = client.start().await.unwrap();
+
+        mock.push_event(Event::DeviceAttributesChanged {
+            device: String::from("B0:CE:18:00:00:01"),
+            attributes: vec![(String::from("brightness"), String::from("40"))],
+            source: DeviceSource::Wifi,
+            times: Default::default(),
+        });
+
+        assert!(matches!(
+            event_handler.poll().await.unwrap(),
+            Event::Connected
+        ));
+        match event_handler.poll().await.unwrap() {
+            Event::DeviceAttributesChanged {
+                device, attributes, ..
+            } => {
+                assert_eq!(device, "B0:CE:18:00:00:01");
+                assert_eq!(
+                    attributes,
+                    vec![(String::from("brightness"), String::from("40"))]
+                );
+            }
+            _ => panic!("expected a DeviceAttributesChanged event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn records_published_and_subscribed_topics() {
+        let (mut client, mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+
+        client.subscribe_device("B0:CE:18:00:00:01").await.unwrap();
+        client
+            .set_device_attribute("B0:CE:18:00:00:01", "switch", "1")
+            .await
+            .unwrap();
+
+        let published = mock.published();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].0, "wifielement/B0:CE:18:00:00:01/update");
+        assert!(published[0].1.contains("\"switch\""));
+        assert_eq!(
+            mock.subscribed()
+                .into_iter()
+                .map(|(topic, _)| topic)
+                .collect::<Vec<_>>(),
+            vec![String::from("wifielement/B0:CE:18:00:00:01/status")]
+        );
+        assert!(mock.published().is_empty());
+    }
+
+    #[tokio::test]
+    async fn uses_queued_responses_once() {
+        let (mut client, mock) = Client::new_mock();
+        let _event_handler = client.start().await.unwrap();
+        mock.respond_with("/device/list.json", 200, json!({ "ret": 1, "msg": "nope" }));
+
+        assert!(matches!(
+            client.wifi_devices().await,
+            Err(Error::Api { .. })
+        ));
+        assert!(client.wifi_devices().await.unwrap().is_empty());
+    }
+}
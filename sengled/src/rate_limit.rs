@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use tokio::{sync::Mutex, time::Instant};
+
+/// A token bucket that allows bursts of up to `rate` publishes, refilled at `rate` per second.
+pub(crate) struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(max_per_second: u32) -> Self {
+        let rate = max_per_second.max(1) as f64;
+
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until a token is available and take it. Waiters are served in order, since the
+    /// bucket is held while sleeping.
+    pub(crate) async fn acquire(&self) {
+        let mut bucket = self.bucket.lock().await;
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+        bucket.refilled_at = now;
+
+        if bucket.tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate);
+            tokio::time::sleep(wait).await;
+
+            bucket.tokens = 1.0;
+            bucket.refilled_at = Instant::now();
+        }
+
+        bucket.tokens -= 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn acquiring_past_the_burst_waits_for_the_rate() {
+        let limiter = RateLimiter::new(10);
+
+        let started = Instant::now();
+        for _ in 0..10 {
+            limiter.acquire().await;
+        }
+        assert!(started.elapsed() < Duration::from_millis(400));
+
+        // the bucket is empty, so each of the next 5 waits for a tenth of a second
+        for _ in 0..5 {
+            limiter.acquire().await;
+        }
+        assert!(started.elapsed() >= Duration::from_millis(490));
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn publishes_respect_the_rate_limit() {
+        let (client, mock) = crate::Client::new_mock();
+        let mut client = client.with_rate_limit(5);
+        let _event_handler = client.start().await.unwrap();
+
+        let started = Instant::now();
+        for _ in 0..8 {
+            client
+                .set_device_attribute("B0:CE:18:00:00:01", "switch", "1")
+                .await
+                .unwrap();
+        }
+
+        assert!(started.elapsed() >= Duration::from_millis(590));
+        assert_eq!(mock.published().len(), 8);
+    }
+}
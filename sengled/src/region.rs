@@ -0,0 +1,113 @@
+/// The region of a Sengled account, which determines the cloud endpoints used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Region {
+    /// The United States, the default.
+    #[default]
+    Us,
+
+    /// Europe.
+    Eu,
+
+    /// Australia.
+    Au,
+}
+
+impl Region {
+    /// The base URL of the account (login) API. The regional hosts follow the naming of the
+    /// US one and are unconfirmed.
+    pub fn ucenter_base(&self) -> &'static str {
+        match self {
+            Self::Us => "https://ucenter.cloud.sengled.com",
+            Self::Eu => "https://eu-ucenter.cloud.sengled.com",
+            Self::Au => "https://au-ucenter.cloud.sengled.com",
+        }
+    }
+
+    /// The base URL of the device API. The regional hosts follow the naming of the US one and
+    /// are unconfirmed.
+    pub fn life2_base(&self) -> &'static str {
+        match self {
+            Self::Us => "https://life2.cloud.sengled.com",
+            Self::Eu => "https://eu-life2.cloud.sengled.com",
+            Self::Au => "https://au-life2.cloud.sengled.com",
+        }
+    }
+
+    /// The base URL of the Zigbee hub API. Like the other non-US hosts, the regional hosts
+    /// follow the naming of the US one and are unconfirmed.
+    pub fn element_base(&self) -> &'static str {
+        match self {
+            Self::Us => "https://element.cloud.sengled.com",
+            Self::Eu => "https://eu-element.cloud.sengled.com",
+            Self::Au => "https://au-element.cloud.sengled.com",
+        }
+    }
+
+    /// The MQTT server used when the server check is skipped.
+    pub fn mqtt_url(&self) -> &'static str {
+        match self {
+            Self::Us => "wss://us-mqtt.cloud.sengled.com:443/mqtt",
+            Self::Eu => "wss://eu-mqtt.cloud.sengled.com:443/mqtt",
+            Self::Au => "wss://au-mqtt.cloud.sengled.com:443/mqtt",
+        }
+    }
+
+    /// The `Host` header the Sengled app sends to the US API, whatever the route. Other regions
+    /// use the host of the route they request.
+    pub(crate) fn host_header(&self) -> Option<&'static str> {
+        match self {
+            Self::Us => Some("element.cloud.sengled.com:443"),
+            Self::Eu | Self::Au => None,
+        }
+    }
+
+    /// Build a route on the account API.
+    pub(crate) fn ucenter_route(&self, path: &str) -> String {
+        format!("{}{}", self.ucenter_base(), path)
+    }
+
+    /// Build a route on the Zigbee hub API.
+    pub(crate) fn element_route(&self, path: &str) -> String {
+        format!("{}{}", self.element_base(), path)
+    }
+
+    /// Build a route on the device API.
+    pub(crate) fn life2_route(&self, path: &str) -> String {
+        format!("{}{}", self.life2_base(), path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_use_the_region_hosts() {
+        for (region, prefix) in [(Region::Us, ""), (Region::Eu, "eu-"), (Region::Au, "au-")] {
+            assert_eq!(
+                region.ucenter_route("/user/app/customer/v2/AuthenCross.json"),
+                format!("https://{prefix}ucenter.cloud.sengled.com/user/app/customer/v2/AuthenCross.json")
+            );
+            assert_eq!(
+                region.life2_route("/life2/device/list.json"),
+                format!("https://{prefix}life2.cloud.sengled.com/life2/device/list.json")
+            );
+            assert_eq!(
+                region.element_route("/zigbee/device/getDeviceDetails.json"),
+                format!(
+                    "https://{prefix}element.cloud.sengled.com/zigbee/device/getDeviceDetails.json"
+                )
+            );
+        }
+
+        assert_eq!(
+            Region::Eu.mqtt_url(),
+            "wss://eu-mqtt.cloud.sengled.com:443/mqtt"
+        );
+        assert_eq!(
+            Region::Us.host_header(),
+            Some("element.cloud.sengled.com:443")
+        );
+        assert_eq!(Region::Au.host_header(), None);
+    }
+}
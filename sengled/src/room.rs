@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::{normalize_mac, AsDeviceMac, Device};
+
+/// A room, or group of devices, as organized in the Sengled app.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Room {
+    #[serde(
+        default,
+        rename(deserialize = "roomId"),
+        deserialize_with = "deserialize_room_id"
+    )]
+    pub id: String,
+
+    #[serde(default, rename(deserialize = "roomName"))]
+    pub name: String,
+
+    #[serde(
+        default,
+        rename(deserialize = "deviceList"),
+        deserialize_with = "deserialize_device_macs"
+    )]
+    pub device_macs: Vec<String>,
+}
+
+/// Group devices by the id of the room they are in, for listing them in sections under each
+/// room's `name`. Rooms are keyed by id since names need not be unique. Devices in no room are
+/// left out, rooms with none of the given devices have an empty group, and devices keep the
+/// order they were given in.
+pub fn group_by_room<'a>(
+    rooms: &[Room],
+    devices: &'a [Device],
+) -> HashMap<String, Vec<&'a Device>> {
+    let mut groups = HashMap::<String, Vec<&Device>>::new();
+    for room in rooms {
+        let macs = room
+            .device_macs
+            .iter()
+            .map(|mac| normalize_mac(mac))
+            .collect::<Vec<_>>();
+
+        let group = groups.entry(room.id.to_owned()).or_default();
+        group.extend(
+            devices
+                .iter()
+                .filter(|device| macs.contains(&device.normalized_mac())),
+        );
+    }
+
+    groups
+}
+
+fn deserialize_room_id<'de, D: Deserializer<'de>>(deserialize: D) -> Result<String, D::Error> {
+    Ok(match Value::deserialize(deserialize)? {
+        Value::String(id) => id,
+        Value::Null => String::new(),
+        other => other.to_string(),
+    })
+}
+
+/// Devices in a room are listed either by their MAC, or as objects with a `deviceUuid`.
+fn deserialize_device_macs<'de, D: Deserializer<'de>>(
+    deserialize: D,
+) -> Result<Vec<String>, D::Error> {
+    let devices = Option::<Vec<Value>>::deserialize(deserialize)?.unwrap_or_default();
+
+    Ok(devices
+        .into_iter()
+        .filter_map(|device| match device {
+            Value::String(mac) => Some(mac),
+            Value::Object(mut device) => match device.remove("deviceUuid") {
+                Some(Value::String(mac)) => Some(mac),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rooms_deserialize_from_the_room_list() {
+        let rooms: Vec<Room> = serde_json::from_value(serde_json::json!([
+            {
+                "roomId": 1021,
+                "roomName": "Bedroom",
+                "deviceList": [
+                    { "deviceUuid": "B0:CE:18:00:00:01", "deviceName": "Lamp" },
+                    "B0:CE:18:00:00:02"
+                ]
+            },
+            { "roomId": "1022", "roomName": "Hallway", "deviceList": null },
+            {}
+        ]))
+        .unwrap();
+
+        assert_eq!(rooms.len(), 3);
+        assert_eq!(rooms[0].id, "1021");
+        assert_eq!(rooms[0].name, "Bedroom");
+        assert_eq!(
+            rooms[0].device_macs,
+            vec!["B0:CE:18:00:00:01", "B0:CE:18:00:00:02"]
+        );
+        assert_eq!(rooms[1].id, "1022");
+        assert!(rooms[1].device_macs.is_empty());
+        assert!(rooms[2].id.is_empty());
+    }
+
+    #[test]
+    fn devices_are_grouped_by_room_id() {
+        let room = |id: &str, macs: &[&str]| Room {
+            id: String::from(id),
+            name: String::from("Bedroom"),
+            device_macs: macs.iter().map(|mac| String::from(*mac)).collect(),
+        };
+        let rooms = [
+            room("1021", &["b0ce18000001"]),
+            room("1022", &[]),
+            room("1023", &["B0:CE:18:00:00:02"]),
+        ];
+        let devices = [
+            Device::test_bulb("B0:CE:18:00:00:01", &[]),
+            Device::test_bulb("B0:CE:18:00:00:02", &[]),
+            Device::test_bulb("B0:CE:18:00:00:03", &[]),
+        ];
+
+        let groups = group_by_room(&rooms, &devices);
+        assert_eq!(groups.len(), 3);
+
+        // rooms sharing a name stay apart
+        let macs = |id: &str| {
+            groups[id]
+                .iter()
+                .map(|device| device.mac.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(macs("1021"), ["B0:CE:18:00:00:01"]);
+        assert!(macs("1022").is_empty());
+        assert_eq!(macs("1023"), ["B0:CE:18:00:00:02"]);
+    }
+}